@@ -0,0 +1,596 @@
+/// Reading and writing of Aseprite (`.ase`/`.aseprite`) files.
+///
+/// Only the first frame is used, since we have no animation. Anything we can't represent (tags,
+/// slices, tilemaps, ...) is skipped and listed in `ImportedCanvas::warnings` instead of failing the
+/// whole import.
+///
+/// See https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md for the format.
+use std::io::{self, Read, Write};
+
+use crate::blend::BlendMode;
+use crate::canvas::{Canvas, LayerId};
+use crate::error::invalid_data;
+use crate::image_io::ImportedCanvas;
+use crate::{Layer, MAX_PIXELS};
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use indexmap::IndexMap;
+use macroquad::prelude::{Color, RED};
+
+pub const ASEPRITE_EXTENSIONS: [&str; 2] = ["aseprite", "ase"];
+
+const HEADER_SIZE: usize = 128;
+const FRAME_HEADER_SIZE: usize = 16;
+const CHUNK_HEADER_SIZE: usize = 6;
+
+const FILE_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_OLD_PALETTE: u16 = 0x0004;
+const CHUNK_OLD_PALETTE_2: u16 = 0x0011;
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_COLOR_PROFILE: u16 = 0x2007;
+const CHUNK_PALETTE: u16 = 0x2019;
+
+const DEPTH_RGBA: u16 = 32;
+const DEPTH_GRAYSCALE: u16 = 16;
+const DEPTH_INDEXED: u16 = 8;
+
+const LAYER_FLAG_VISIBLE: u16 = 1;
+const LAYER_FLAG_EDITABLE: u16 = 2;
+const HEADER_FLAG_LAYER_OPACITY: u32 = 1;
+
+const BLEND_NORMAL: u16 = 0;
+const BLEND_MULTIPLY: u16 = 1;
+const BLEND_SCREEN: u16 = 2;
+const BLEND_OVERLAY: u16 = 3;
+const BLEND_ADDITION: u16 = 16;
+
+const LAYER_TYPE_NORMAL: u16 = 0;
+const LAYER_TYPE_GROUP: u16 = 1;
+
+const CEL_TYPE_RAW: u16 = 0;
+const CEL_TYPE_LINKED: u16 = 1;
+const CEL_TYPE_COMPRESSED: u16 = 2;
+
+pub fn is_aseprite_path(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .is_some_and(|e| ASEPRITE_EXTENSIONS.iter().any(|a| e.eq_ignore_ascii_case(a)))
+}
+
+struct AseLayer {
+    flags: u16,
+    kind: u16,
+    /// None if we don't have it.
+    blend_mode: Option<BlendMode>,
+    /// 0-255, already including the header flag that says whether it is valid.
+    opacity: u8,
+    name: String,
+}
+
+struct AseCel {
+    layer_index: usize,
+    x: i64,
+    y: i64,
+    opacity: u8,
+    width: usize,
+    height: usize,
+    /// Pixels in the file's color depth.
+    data: Vec<u8>,
+}
+
+pub fn read<R: Read>(r: &mut R) -> io::Result<ImportedCanvas> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+
+    let mut h = data.get(..HEADER_SIZE).ok_or_else(|| invalid_data("file too short"))?;
+    let _file_size = read_u32(&mut h)?;
+    if read_u16(&mut h)? != FILE_MAGIC {
+        return Err(invalid_data("not an Aseprite file"));
+    }
+    let frames = read_u16(&mut h)?;
+    let width = read_u16(&mut h)? as usize;
+    let height = read_u16(&mut h)? as usize;
+    let depth = read_u16(&mut h)?;
+    let flags = read_u32(&mut h)?;
+    skip(&mut h, 2 + 4 + 4)?;
+    let transparent_index = read_u8(&mut h)?;
+
+    if frames == 0 {
+        return Err(invalid_data("file has no frames"));
+    }
+    if ![DEPTH_RGBA, DEPTH_GRAYSCALE, DEPTH_INDEXED].contains(&depth) {
+        return Err(invalid_data(format!("unsupported color depth {depth}")));
+    }
+    if !(1..=MAX_PIXELS).contains(&width) || !(1..=MAX_PIXELS).contains(&height) {
+        return Err(invalid_data(format!(
+            "sprite must be between 1 and {MAX_PIXELS} pixels wide and high"
+        )));
+    }
+
+    let mut warnings = Vec::new();
+    if frames > 1 {
+        warnings.push(format!("Only the first of {frames} frames was imported."));
+    }
+
+    // First frame
+    let mut f = &data[HEADER_SIZE..];
+    let frame_size = read_u32(&mut f)? as usize;
+    if read_u16(&mut f)? != FRAME_MAGIC {
+        return Err(invalid_data("invalid frame header"));
+    }
+    let old_num_chunks = read_u16(&mut f)? as usize;
+    skip(&mut f, 2 + 2)?;
+    let new_num_chunks = read_u32(&mut f)? as usize;
+    let num_chunks = if new_num_chunks == 0 { old_num_chunks } else { new_num_chunks };
+
+    let frame_end = (HEADER_SIZE + frame_size).min(data.len());
+    let mut chunks = data
+        .get(HEADER_SIZE + FRAME_HEADER_SIZE..frame_end)
+        .ok_or_else(|| invalid_data("invalid frame size"))?;
+
+    let mut layers = Vec::<AseLayer>::new();
+    let mut cels = Vec::<AseCel>::new();
+    let mut palette = Vec::<[u8; 4]>::new();
+    let mut has_new_palette = false;
+    let mut ignored = Vec::<&str>::new();
+
+    for _ in 0..num_chunks {
+        let size = read_u32(&mut chunks)? as usize;
+        let kind = read_u16(&mut chunks)?;
+        let body_len = size
+            .checked_sub(CHUNK_HEADER_SIZE)
+            .filter(|l| *l <= chunks.len())
+            .ok_or_else(|| invalid_data("invalid chunk size"))?;
+        let (mut body, rest) = chunks.split_at(body_len);
+        chunks = rest;
+
+        match kind {
+            CHUNK_LAYER => {
+                let layer_flags = read_u16(&mut body)?;
+                let layer_kind = read_u16(&mut body)?;
+                skip(&mut body, 2 + 2 + 2)?;
+                let blend_mode = match read_u16(&mut body)? {
+                    BLEND_NORMAL => Some(BlendMode::Normal),
+                    BLEND_MULTIPLY => Some(BlendMode::Multiply),
+                    BLEND_SCREEN => Some(BlendMode::Screen),
+                    BLEND_OVERLAY => Some(BlendMode::Overlay),
+                    BLEND_ADDITION => Some(BlendMode::Add),
+                    _ => None,
+                };
+                let mut opacity = read_u8(&mut body)?;
+                if flags & HEADER_FLAG_LAYER_OPACITY == 0 {
+                    opacity = 255;
+                }
+                skip(&mut body, 3)?;
+                layers.push(AseLayer {
+                    flags: layer_flags,
+                    kind: layer_kind,
+                    blend_mode,
+                    opacity,
+                    name: read_string(&mut body)?,
+                });
+            }
+            CHUNK_CEL => {
+                let layer_index = read_u16(&mut body)? as usize;
+                let x = read_i16(&mut body)? as i64;
+                let y = read_i16(&mut body)? as i64;
+                let opacity = read_u8(&mut body)?;
+                let cel_kind = read_u16(&mut body)?;
+                skip(&mut body, 2 + 5)?;
+
+                let bytes_per_pixel = (depth / 8) as usize;
+                let (width, height, data) = match cel_kind {
+                    CEL_TYPE_RAW | CEL_TYPE_COMPRESSED => {
+                        let w = read_u16(&mut body)? as usize;
+                        let h = read_u16(&mut body)? as usize;
+                        // Cels are normally no bigger than the sprite, don't allocate absurd sizes
+                        if w > 4 * width || h > 4 * height {
+                            return Err(invalid_data("cel is much larger than the sprite"));
+                        }
+                        let len = w * h * bytes_per_pixel;
+                        let mut pixels = vec![0u8; len];
+                        if cel_kind == CEL_TYPE_RAW {
+                            body.read_exact(&mut pixels)?;
+                        } else {
+                            ZlibDecoder::new(body).read_exact(&mut pixels)?;
+                        }
+                        (w, h, pixels)
+                    }
+                    // Links point to cels of earlier frames, which the first frame can't have
+                    CEL_TYPE_LINKED => continue,
+                    _ => {
+                        ignored.push("tilemap cels");
+                        continue;
+                    }
+                };
+                cels.push(AseCel {
+                    layer_index,
+                    x,
+                    y,
+                    opacity,
+                    width,
+                    height,
+                    data,
+                });
+            }
+            CHUNK_PALETTE => {
+                has_new_palette = true;
+                let size = read_u32(&mut body)? as usize;
+                let first = read_u32(&mut body)? as usize;
+                let last = read_u32(&mut body)? as usize;
+                skip(&mut body, 8)?;
+                if last < first || last.max(size) > u16::MAX as usize {
+                    return Err(invalid_data("invalid palette"));
+                }
+                palette.resize(palette.len().max(size).max(last + 1), [0, 0, 0, 255]);
+                for entry in palette[first..=last].iter_mut() {
+                    let entry_flags = read_u16(&mut body)?;
+                    let mut rgba = [0u8; 4];
+                    body.read_exact(&mut rgba)?;
+                    *entry = rgba;
+                    if entry_flags & 1 != 0 {
+                        read_string(&mut body)?;
+                    }
+                }
+            }
+            CHUNK_OLD_PALETTE if !has_new_palette => {
+                let packets = read_u16(&mut body)?;
+                let mut index = 0usize;
+                for _ in 0..packets {
+                    index += read_u8(&mut body)? as usize;
+                    let count = match read_u8(&mut body)? {
+                        0 => 256,
+                        n => n as usize,
+                    };
+                    palette.resize(palette.len().max(index + count), [0, 0, 0, 255]);
+                    for entry in palette[index..index + count].iter_mut() {
+                        let mut rgb = [0u8; 3];
+                        body.read_exact(&mut rgb)?;
+                        *entry = [rgb[0], rgb[1], rgb[2], 255];
+                    }
+                    index += count;
+                }
+            }
+            // Nothing in these that we need
+            CHUNK_OLD_PALETTE | CHUNK_OLD_PALETTE_2 | CHUNK_COLOR_PROFILE => {}
+            other => ignored.push(chunk_name(other)),
+        }
+    }
+
+    ignored.sort_unstable();
+    ignored.dedup();
+    if !ignored.is_empty() {
+        warnings.push(format!("Ignored unsupported data: {}.", ignored.join(", ")));
+    }
+    if layers.iter().any(|l| l.kind == LAYER_TYPE_NORMAL && l.blend_mode.is_none()) {
+        warnings.push("Unsupported layer blend modes were replaced with Normal.".to_string());
+    }
+    if layers.iter().any(|l| l.kind == LAYER_TYPE_GROUP) {
+        warnings.push("Layer groups were flattened.".to_string());
+    }
+    if layers.iter().any(|l| l.kind > LAYER_TYPE_GROUP) {
+        warnings.push("Tilemap layers were skipped.".to_string());
+    }
+
+    // Aseprite lists layers bottom to top, same as us
+    let mut canvas_layers = IndexMap::<LayerId, Layer>::new();
+    let mut ids = vec![None; layers.len()];
+    for (i, l) in layers.iter().enumerate() {
+        if l.kind == LAYER_TYPE_NORMAL {
+            let id = LayerId::new_v4();
+            let mut layer = Layer::named(width, height, l.name.clone());
+            layer.visible = l.flags & LAYER_FLAG_VISIBLE != 0;
+            layer.locked = l.flags & LAYER_FLAG_EDITABLE == 0;
+            layer.opacity = l.opacity as f32 / 255.;
+            layer.blend_mode = l.blend_mode.unwrap_or_default();
+            canvas_layers.insert(id, layer);
+            ids[i] = Some(id);
+        }
+    }
+    if canvas_layers.is_empty() {
+        canvas_layers.insert(LayerId::new_v4(), Layer::named(width, height, "Layer 0"));
+    }
+
+    for cel in cels.iter() {
+        let Some(Some(id)) = ids.get(cel.layer_index) else { continue };
+        let layer = canvas_layers.get_mut(id).unwrap();
+
+        for cy in 0..cel.height {
+            for cx in 0..cel.width {
+                let x = cel.x + cx as i64;
+                let y = cel.y + cy as i64;
+                if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                    continue;
+                }
+                let i = cx + cy * cel.width;
+                let rgba = match depth {
+                    DEPTH_RGBA => {
+                        let p = &cel.data[i * 4..i * 4 + 4];
+                        [p[0], p[1], p[2], p[3]]
+                    }
+                    DEPTH_GRAYSCALE => {
+                        let p = &cel.data[i * 2..i * 2 + 2];
+                        [p[0], p[0], p[0], p[1]]
+                    }
+                    _ => {
+                        let index = cel.data[i];
+                        if index == transparent_index {
+                            continue;
+                        }
+                        palette.get(index as usize).copied().unwrap_or([0, 0, 0, 255])
+                    }
+                };
+
+                // Cel opacities are baked into the pixels since we only have the one cel per layer
+                let alpha = rgba[3] as u32 * cel.opacity as u32 / 255;
+                if alpha == 0 {
+                    continue;
+                }
+                let c = Color::from_rgba(rgba[0], rgba[1], rgba[2], alpha as u8);
+                layer.set_pixel_color(x as usize + y as usize * width, c);
+            }
+        }
+    }
+
+    let active = *canvas_layers.last().unwrap().0;
+    Ok(ImportedCanvas {
+        canvas: Canvas::from_layers(width, height, canvas_layers, active, RED),
+        warnings,
+    })
+}
+
+/// Writes a single frame RGBA sprite with one layer (and cel) per canvas layer.
+pub fn write<W: Write>(w: &mut W, canvas: &Canvas) -> io::Result<()> {
+    let too_large = |_| invalid_data("canvas too large");
+    let width = u16::try_from(canvas.width()).map_err(too_large)?;
+    let height = u16::try_from(canvas.height()).map_err(too_large)?;
+
+    let mut chunks = Vec::<Vec<u8>>::new();
+
+    let palette = used_colors(canvas);
+    let mut body = Vec::new();
+    put_u32(&mut body, palette.len() as u32);
+    put_u32(&mut body, 0);
+    put_u32(&mut body, palette.len().saturating_sub(1) as u32);
+    body.extend_from_slice(&[0; 8]);
+    for c in palette.iter() {
+        put_u16(&mut body, 0);
+        body.extend_from_slice(c);
+    }
+    chunks.push(chunk(CHUNK_PALETTE, &body));
+
+    for (_, layer) in canvas.layers().iter() {
+        let mut layer_flags = 0;
+        if layer.visible {
+            layer_flags |= LAYER_FLAG_VISIBLE;
+        }
+        if !layer.locked {
+            layer_flags |= LAYER_FLAG_EDITABLE;
+        }
+        let blend_mode = match layer.blend_mode {
+            BlendMode::Normal => BLEND_NORMAL,
+            BlendMode::Multiply => BLEND_MULTIPLY,
+            BlendMode::Screen => BLEND_SCREEN,
+            BlendMode::Overlay => BLEND_OVERLAY,
+            BlendMode::Add => BLEND_ADDITION,
+        };
+
+        let mut body = Vec::new();
+        put_u16(&mut body, layer_flags);
+        put_u16(&mut body, LAYER_TYPE_NORMAL);
+        put_u16(&mut body, 0); // child level
+        put_u16(&mut body, 0); // default width
+        put_u16(&mut body, 0); // default height
+        put_u16(&mut body, blend_mode);
+        body.push((layer.opacity * 255.).round() as u8);
+        body.extend_from_slice(&[0; 3]);
+        put_string(&mut body, &layer.name);
+        chunks.push(chunk(CHUNK_LAYER, &body));
+    }
+
+    for (i, (_, layer)) in canvas.layers().iter().enumerate() {
+        if layer.is_empty() {
+            continue;
+        }
+        let mut pixels = Vec::with_capacity(canvas.width() * canvas.height() * 4);
+        for p in layer.data.iter() {
+            let rgba: [u8; 4] = p.map(Into::into).unwrap_or([0; 4]);
+            pixels.extend_from_slice(&rgba);
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&pixels)?;
+
+        let mut body = Vec::new();
+        put_u16(&mut body, i as u16);
+        put_u16(&mut body, 0); // x
+        put_u16(&mut body, 0); // y
+        body.push(255); // opacity
+        put_u16(&mut body, CEL_TYPE_COMPRESSED);
+        put_u16(&mut body, 0); // z-index
+        body.extend_from_slice(&[0; 5]);
+        put_u16(&mut body, width);
+        put_u16(&mut body, height);
+        body.extend_from_slice(&encoder.finish()?);
+        chunks.push(chunk(CHUNK_CEL, &body));
+    }
+
+    let frame_size = FRAME_HEADER_SIZE + chunks.iter().map(Vec::len).sum::<usize>();
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    put_u32(&mut header, (HEADER_SIZE + frame_size) as u32);
+    put_u16(&mut header, FILE_MAGIC);
+    put_u16(&mut header, 1); // frames
+    put_u16(&mut header, width);
+    put_u16(&mut header, height);
+    put_u16(&mut header, DEPTH_RGBA);
+    put_u32(&mut header, HEADER_FLAG_LAYER_OPACITY);
+    put_u16(&mut header, 100); // speed
+    put_u32(&mut header, 0);
+    put_u32(&mut header, 0);
+    header.push(0); // transparent index
+    header.extend_from_slice(&[0; 3]);
+    put_u16(&mut header, palette.len() as u16);
+    header.push(1); // pixel width
+    header.push(1); // pixel height
+    header.resize(HEADER_SIZE, 0);
+    w.write_all(&header)?;
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE);
+    put_u32(&mut frame, frame_size as u32);
+    put_u16(&mut frame, FRAME_MAGIC);
+    put_u16(&mut frame, chunks.len().min(0xFFFF) as u16);
+    put_u16(&mut frame, 100); // duration
+    frame.extend_from_slice(&[0; 2]);
+    put_u32(&mut frame, chunks.len() as u32);
+    w.write_all(&frame)?;
+
+    for c in chunks.iter() {
+        w.write_all(c)?;
+    }
+    Ok(())
+}
+
+/// The distinct colors of all layers (at most 256, the size of an Aseprite palette).
+fn used_colors(canvas: &Canvas) -> Vec<[u8; 4]> {
+    let mut colors = Vec::<[u8; 4]>::new();
+    for (_, layer) in canvas.layers().iter() {
+        for c in layer.data.iter().flatten() {
+            let rgba: [u8; 4] = (*c).into();
+            if !colors.contains(&rgba) {
+                colors.push(rgba);
+                if colors.len() == 256 {
+                    return colors;
+                }
+            }
+        }
+    }
+    if colors.is_empty() {
+        colors.push([0, 0, 0, 255]);
+    }
+    colors
+}
+
+fn chunk_name(kind: u16) -> &'static str {
+    match kind {
+        0x2006 => "cel extras",
+        0x2008 => "external files",
+        0x2016 => "masks",
+        0x2017 => "paths",
+        0x2018 => "tags",
+        0x2020 => "user data",
+        0x2022 => "slices",
+        0x2023 => "tilesets",
+        _ => "unknown chunks",
+    }
+}
+
+fn chunk(kind: u16, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(CHUNK_HEADER_SIZE + body.len());
+    put_u32(&mut out, (CHUNK_HEADER_SIZE + body.len()) as u32);
+    put_u16(&mut out, kind);
+    out.extend_from_slice(body);
+    out
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) {
+    put_u16(out, s.len() as u16);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn skip(r: &mut &[u8], n: usize) -> io::Result<()> {
+    if r.len() < n {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    *r = &r[n..];
+    Ok(())
+}
+
+fn read_u8(r: &mut &[u8]) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16(r: &mut &[u8]) -> io::Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+fn read_i16(r: &mut &[u8]) -> io::Result<i16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(i16::from_le_bytes(b))
+}
+
+fn read_u32(r: &mut &[u8]) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_string(r: &mut &[u8]) -> io::Result<String> {
+    let len = read_u16(r)? as usize;
+    let mut b = vec![0u8; len];
+    r.read_exact(&mut b)?;
+    Ok(String::from_utf8_lossy(&b).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(c: Option<Color>) -> Option<[u8; 4]> {
+        c.map(|c| c.into())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut canvas = Canvas::new(4, 3);
+        canvas.set_pixel_at(0, Some(Color::from_rgba(10, 20, 30, 255)));
+        canvas.set_pixel_at(11, Some(Color::from_rgba(200, 100, 0, 128)));
+        let top = canvas.create_new_layer().layer_id;
+        let layer = canvas.get_layer_mut(top).unwrap();
+        layer.name = "Shading".to_string();
+        layer.visible = false;
+        layer.locked = true;
+        layer.opacity = 51. / 255.;
+        layer.blend_mode = BlendMode::Screen;
+        layer.set_pixel_color(5, Color::from_rgba(1, 2, 3, 4));
+
+        let mut bytes = Vec::new();
+        write(&mut bytes, &canvas).unwrap();
+        let import = read(&mut &bytes[..]).unwrap();
+        let read = import.canvas;
+
+        assert!(import.warnings.is_empty(), "{:?}", import.warnings);
+        assert_eq!((read.width(), read.height()), (4, 3));
+        assert_eq!(read.layers().len(), 2);
+        for (a, b) in canvas.layers().values().zip(read.layers().values()) {
+            assert_eq!(a.name, b.name);
+            assert_eq!((a.visible, a.locked, a.blend_mode), (b.visible, b.locked, b.blend_mode));
+            assert_eq!(a.opacity, b.opacity);
+            let pixels = |l: &Layer| l.data.iter().map(|c| rgba(*c)).collect::<Vec<_>>();
+            assert_eq!(pixels(a), pixels(b));
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(read(&mut &b"not an aseprite file"[..]).is_err());
+        assert!(read(&mut &[0u8; HEADER_SIZE][..]).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+/// Applying the same operations to every sprite in a directory tree, used by the `batch` command.
+///
+/// Every file is loaded, remapped to a palette, flattened, trimmed and upscaled (each step only if
+/// asked for) and written to the same relative path in the output directory. Files that none of
+/// that changes are copied as they are.
+use std::path::{Path, PathBuf};
+
+use crate::aseprite;
+use crate::canvas::{Canvas, LayerId};
+use crate::error::{ProjectError, ProjectResult};
+use crate::image_io::{self, ExportFormat, OPEN_IMAGE_EXTENSIONS};
+use crate::openraster;
+use crate::palette;
+use crate::project::{self, SaveOptions};
+use crate::project_file;
+use crate::svg;
+
+use macroquad::prelude::Color;
+
+#[derive(Debug, Default)]
+pub struct BatchOptions {
+    pub scale: u32,
+    /// Every pixel is replaced with the closest palette color.
+    pub palette: Option<Vec<Color>>,
+    /// Crop to the bounds of the non-transparent pixels. Only possible for image outputs, layered
+    /// formats keep the whole canvas.
+    pub trim: bool,
+    /// Extension of the output files. The input extension is kept if None.
+    pub format: Option<String>,
+    /// Only report what would be written.
+    pub dry_run: bool,
+}
+
+pub struct BatchEntry {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// What was done to the file, or why it failed.
+    pub result: Result<Vec<String>, String>,
+}
+
+/// Returns true if `path` is a file the editor can open.
+pub fn is_input_path(path: &Path) -> bool {
+    let Some(path) = path.to_str() else { return false };
+    project_file::is_project_path(path)
+        || aseprite::is_aseprite_path(path)
+        || openraster::is_openraster_path(path)
+        || Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| OPEN_IMAGE_EXTENSIONS.iter().any(|x| x.eq_ignore_ascii_case(e)))
+}
+
+/// Every openable file below `dir`, sorted so the report is in a stable order.
+pub fn find_inputs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if is_input_path(&path) {
+                out.push(path);
+            }
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+/// Processes every file below `input_dir`. A file that fails doesn't stop the others, its error
+/// is in its entry instead.
+pub fn run(
+    input_dir: &Path,
+    output_dir: &Path,
+    opts: &BatchOptions,
+) -> std::io::Result<Vec<BatchEntry>> {
+    let mut entries = Vec::new();
+    for input in find_inputs(input_dir)? {
+        let relative = input.strip_prefix(input_dir).unwrap_or(&input);
+        let mut output = output_dir.join(relative);
+        if let Some(ext) = &opts.format {
+            output.set_extension(ext);
+        }
+
+        let result = process(&input, &output, opts).map_err(|e| e.to_string());
+        entries.push(BatchEntry { input, output, result });
+    }
+    Ok(entries)
+}
+
+fn process(input: &Path, output: &Path, opts: &BatchOptions) -> ProjectResult<Vec<String>> {
+    let input_str = input.to_str().ok_or_else(|| ProjectError::NonUtf8Path(input.into()))?;
+    let output_str = output.to_str().ok_or_else(|| ProjectError::NonUtf8Path(output.into()))?;
+
+    let (project_name, import) = project::load(input_str)?;
+    let mut changes = import.warnings;
+    let mut canvas = import.canvas;
+    // Files that end up the same are copied rather than encoded again, which could lose whatever
+    // the warnings are about or change the compression
+    let mut modified = false;
+
+    if let Some(colors) = &opts.palette {
+        let remapped = remap(&mut canvas, colors);
+        if remapped > 0 {
+            changes.push(format!("{remapped} pixels remapped to the palette"));
+            modified = true;
+        }
+    }
+
+    if output.extension() != input.extension() {
+        let ext = output.extension().unwrap_or_default().to_string_lossy();
+        changes.push(format!("converted to {ext}"));
+        modified = true;
+    }
+
+    if !opts.dry_run
+        && let Some(dir) = output.parent()
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // Layered formats can't be trimmed or scaled (only SVG is scaled), so those are written as
+    // they are
+    let Some(format) = ExportFormat::from_path(output_str) else {
+        let mut notes = Vec::new();
+        if opts.trim {
+            notes.push("not trimmed, the format keeps the whole canvas".to_string());
+        }
+        if opts.scale > 1 && !svg::is_svg_path(output_str) {
+            notes.push("not scaled, the format keeps the whole canvas".to_string());
+        }
+        if !modified {
+            copy_unchanged(input, output, opts)?;
+            return Ok(notes);
+        }
+        changes.extend(notes);
+        let mut save_options = SaveOptions::default();
+        save_options.set_scale(opts.scale);
+        if !opts.dry_run {
+            project::save_canvas(output_str, &project_name, &canvas, &save_options)?;
+        }
+        return Ok(changes);
+    };
+
+    let mut img = image_io::rgba_image_from_flipped(&canvas.to_image_all());
+    if opts.trim {
+        let (w, h) = img.dimensions();
+        if let Some(trimmed) = image_io::trim_transparent(&img)
+            && trimmed.dimensions() != (w, h)
+        {
+            let (tw, th) = trimmed.dimensions();
+            changes.push(format!("trimmed from {w}x{h} to {tw}x{th}"));
+            img = trimmed;
+            modified = true;
+        }
+    }
+    if opts.scale > 1 {
+        img = image_io::upscale(&img, opts.scale);
+        let (w, h) = img.dimensions();
+        changes.push(format!("scaled to {w}x{h}"));
+        modified = true;
+    }
+
+    if !modified {
+        copy_unchanged(input, output, opts)?;
+        return Ok(Vec::new());
+    }
+    if !opts.dry_run {
+        image_io::export_image(&img, output_str, format, &Default::default())?;
+    }
+    Ok(changes)
+}
+
+/// Copies `input` to `output` byte for byte.
+fn copy_unchanged(input: &Path, output: &Path, opts: &BatchOptions) -> ProjectResult<()> {
+    // Copying a file onto itself would empty it
+    let same_file = output.canonicalize().ok() == Some(input.canonicalize()?);
+    if !opts.dry_run && !same_file {
+        std::fs::copy(input, output)?;
+    }
+    Ok(())
+}
+
+/// Replaces the pixels of every layer with their closest palette color. Returns how many pixels
+/// changed.
+fn remap(canvas: &mut Canvas, colors: &[Color]) -> usize {
+    let ids = canvas.layers().keys().copied().collect::<Vec<LayerId>>();
+    let mut changed = 0;
+    for id in ids {
+        let layer = canvas.get_layer_mut(id).unwrap();
+        for pixel in layer.data.iter_mut().flatten() {
+            let Some(new) = palette::nearest(colors, *pixel) else { return 0 };
+            let (old_rgba, new_rgba): ([u8; 4], [u8; 4]) = ((*pixel).into(), new.into());
+            if old_rgba != new_rgba {
+                *pixel = new;
+                changed += 1;
+            }
+        }
+    }
+    if changed > 0 {
+        canvas.layers_changed();
+    }
+    changed
+}
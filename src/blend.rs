@@ -0,0 +1,109 @@
+/// How the pixels of a layer are combined with the layers below it.
+///
+/// The modes are the separable blend modes of the W3C compositing spec, followed by normal
+/// source-over compositing: https://www.w3.org/TR/compositing-1/
+use macroquad::prelude::Color;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Add,
+}
+
+impl BlendMode {
+    /// In the order they are stored in project files, don't reorder.
+    pub const ALL: [BlendMode; 5] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Add,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Normal",
+            BlendMode::Multiply => "Multiply",
+            BlendMode::Screen => "Screen",
+            BlendMode::Overlay => "Overlay",
+            BlendMode::Add => "Add",
+        }
+    }
+
+    /// Blends one channel of the layer (`s`) with the one below it (`d`).
+    fn channel(self, d: f32, s: f32) -> f32 {
+        match self {
+            BlendMode::Normal => s,
+            BlendMode::Multiply => d * s,
+            BlendMode::Screen => d + s - d * s,
+            BlendMode::Overlay => {
+                if d <= 0.5 {
+                    2.0 * d * s
+                } else {
+                    1.0 - 2.0 * (1.0 - d) * (1.0 - s)
+                }
+            }
+            BlendMode::Add => (d + s).min(1.0),
+        }
+    }
+}
+
+/// Draws `src` over `dst` with `mode`, after multiplying its alpha with `opacity`. Neither color
+/// is premultiplied.
+pub fn composite(dst: Option<Color>, src: Color, mode: BlendMode, opacity: f32) -> Color {
+    let src_a = src.a * opacity;
+    let Some(dst) = dst else { return Color { a: src_a, ..src } };
+    let a = src_a + dst.a * (1.0 - src_a);
+    if a <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0, 0.0);
+    }
+    // Where the layer below is transparent, the layer is drawn as it is
+    let mix = |s: f32, d: f32| {
+        let blended = (1.0 - dst.a) * s + dst.a * mode.channel(d, s);
+        (blended * src_a + d * dst.a * (1.0 - src_a)) / a
+    };
+    Color::new(mix(src.r, dst.r), mix(src.g, dst.g), mix(src.b, dst.b), a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(c: Color) -> [u8; 4] {
+        c.into()
+    }
+
+    #[test]
+    fn normal_is_source_over() {
+        let red = Color::new(1., 0., 0., 1.);
+        let blue = Color::new(0., 0., 1., 1.);
+        assert_eq!(rgba(composite(Some(blue), red, BlendMode::Normal, 1.)), [255, 0, 0, 255]);
+        assert_eq!(rgba(composite(Some(blue), red, BlendMode::Normal, 0.)), [0, 0, 255, 255]);
+        assert_eq!(rgba(composite(Some(blue), red, BlendMode::Normal, 0.5)), [127, 0, 127, 255]);
+        assert_eq!(rgba(composite(None, red, BlendMode::Normal, 0.5)), [255, 0, 0, 127]);
+    }
+
+    #[test]
+    fn separable_modes() {
+        let dst = Color::new(0.5, 0.25, 1., 1.);
+        let src = Color::new(0.5, 1., 0., 1.);
+        let blend = |mode| rgba(composite(Some(dst), src, mode, 1.));
+        assert_eq!(blend(BlendMode::Multiply), [63, 63, 0, 255]);
+        assert_eq!(blend(BlendMode::Screen), [191, 255, 255, 255]);
+        assert_eq!(blend(BlendMode::Overlay), [127, 127, 255, 255]);
+        assert_eq!(blend(BlendMode::Add), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn blend_modes_ignore_transparent_pixels_below() {
+        let dst = Color::new(1., 1., 1., 0.);
+        let src = Color::new(0.2, 0.4, 0.6, 1.);
+        for mode in BlendMode::ALL {
+            assert_eq!(rgba(composite(Some(dst), src, mode, 1.)), rgba(src));
+        }
+    }
+}
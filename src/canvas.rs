@@ -1,7 +1,10 @@
 use crate::RECT_DIMS;
 
+use crate::blend::{self, BlendMode};
+use crate::fill::{self, FillOptions};
+
 use crate::tool_helper;
-use crate::undo_redo::{DrawPixelsInfo,DrawInfo};
+use crate::undo_redo::{DrawPixelsInfo,DrawInfo,LayerInfo,MergeLayersInfo,MoveLayerInfo};
 use crate::{FillType, Layer, Tool, ToolInfo, ToolKind};
 
 use macroquad::prelude::*;
@@ -15,22 +18,35 @@ pub type LayerId = Uuid;
 /// previews etc.
 #[derive(Default)]
 pub struct Canvas {
-    num_pixels: usize,
+    width: usize,
+    height: usize,
     active_layer: LayerId ,
     layers: IndexMap<Uuid,Layer>,
     tool: Tool,
     selected_color: Color,
+    /// Colors the user picked to work with, loaded from palette files or added by hand.
+    palette: Vec<Color>,
+    fill_options: FillOptions,
+    /// Increased on every change to the layers, so that `texture` knows when it is outdated.
+    revision: u64,
+    /// The flattened layers as drawn on screen, and the revision they were rendered at.
+    texture: Option<(u64, Texture2D)>,
+    /// The top left and bottom right pixel of what changed since `texture` was rendered, so that
+    /// drawing a few pixels doesn't upload the whole canvas again.
+    dirty: Option<((usize, usize), (usize, usize))>,
+    background: Option<Texture2D>,
 }
 
 impl Canvas {
-    pub fn new(num_pixels: usize) -> Self {
+    pub fn new(width: usize, height: usize) -> Self {
         let mut map = IndexMap::<LayerId,Layer>::new();
-        let layer = Layer::new(num_pixels);
+        let layer = Layer::named(width, height, "Layer 0");
         let id = LayerId::new_v4();
         map.insert(id,layer);
 
         Self {
-            num_pixels,
+            width,
+            height,
             layers: map,
             active_layer: id,
             selected_color: RED,
@@ -38,6 +54,25 @@ impl Canvas {
         }
     }
 
+    /// Builds a canvas out of already existing layers, e.g. when loading a saved project.
+    /// `active_layer` must be one of the ids in `layers`, which must all be `width` by `height`.
+    pub fn from_layers(
+        width: usize,
+        height: usize,
+        layers: IndexMap<LayerId, Layer>,
+        active_layer: LayerId,
+        selected_color: Color,
+    ) -> Self {
+        Self {
+            width,
+            height,
+            layers,
+            active_layer,
+            selected_color,
+            ..Default::default()
+        }
+    }
+
     pub fn get_pos_from_tool_info(
         &self,
         camera: &Camera2D,
@@ -72,9 +107,22 @@ impl Canvas {
         }
     }
 
+    /// The pixel coordinates of the top left and bottom right corners of the area between the
+    /// two tool positions.
+    pub fn get_pixel_corners_from_tool_info(
+        &self,
+        camera: &Camera2D,
+        info: &ToolInfo,
+    ) -> Option<((usize, usize), (usize, usize))> {
+        let (Some(i), Some(f)) = (info.initial_loc, info.final_loc) else { return None };
+        let a = self.get_pixel_index_from_position(camera.screen_to_world(i))?;
+        let b = self.get_pixel_index_from_position(camera.screen_to_world(f))?;
+        let (ax, ay, bx, by) = (a % self.width, a / self.width, b % self.width, b / self.width);
+        Some(((ax.min(bx), ay.min(by)), (ax.max(bx), ay.max(by))))
+    }
+
     /// Returns the pixels to color based on the current tool. Only really useful for Line and Fill,
-    /// otherwise we can just make do with get_pos_from_rect_border_info which will take O(n)
-    /// This on the otherhand, combined with the drawing, takes O(n^2)
+    /// otherwise we can just make do with get_pixel_corners_from_tool_info
     ///
     /// ALWAYS returns a vector (empty if no pixels found) if its a Fill or a Line tool, otherwise
     /// returns None
@@ -86,180 +134,122 @@ impl Canvas {
     ) -> Option<Vec<usize>> {
         match kind {
             ToolKind::Fill => {
-                let mut out = Vec::<usize>::new();
-
                 let cpos = camera.screen_to_world(mouse_position().into());
-                if let Some(index) = self.get_pixel_index_from_position(cpos) {
-                    let c = self.active_layer().unwrap().data[index];
-                    self.use_fill(&mut out, c, index);
-                }
-                Some(out)
+                let Some(index) = self.get_pixel_index_from_position(cpos) else {
+                    return Some(Vec::new());
+                };
+
+                let opts = &self.fill_options;
+                let active_layer = self.active_layer().unwrap();
+                Some(fill::flood_fill(self.width, self.height, index, opts, |i| {
+                    if opts.sample_all_layers {
+                        self.flattened_pixel(i)
+                    } else {
+                        active_layer.data[i]
+                    }
+                }))
             }
             ToolKind::Line => {
 
-                if info.initial_loc.is_none() || info.final_loc.is_none() {
+                let (Some(start), Some(end)) = (info.initial_loc, info.final_loc) else {
                     return Some(Vec::new());
-                }
-
-                let res = self.get_pos_from_tool_info_unordered(camera, info);
-                if res.is_none() {
+                };
+                let si = self.get_pixel_index_from_position(camera.screen_to_world(start));
+                let ei = self.get_pixel_index_from_position(camera.screen_to_world(end));
+                let (Some(si), Some(ei)) = (si, ei) else {
                     return Some(Vec::new());
-                }
-                let (start, end) = res.unwrap();
-
-                let si = self.get_pixel_index_from_position(start).unwrap();
-                let ei = self.get_pixel_index_from_position(end).unwrap();
+                };
 
-                let x0 = si % self.num_pixels;
-                let y0 = si / self.num_pixels;
+                let x0 = si % self.width;
+                let y0 = si / self.width;
 
-                let x1 = ei % self.num_pixels;
-                let y1 = ei / self.num_pixels;
+                let x1 = ei % self.width;
+                let y1 = ei / self.width;
 
-                return Some(tool_helper::get_line_pixels(self.num_pixels,(x0,y0),(x1,y1)));
+                return Some(tool_helper::get_line_pixels(self.width,(x0,y0),(x1,y1)));
             }
             _ => None,
         }
     }
 
-    fn use_fill(&self, res: &mut Vec<usize>, color_to_replace: Option<Color>, cur_index: usize) {
-        let active_layer = self.active_layer().unwrap();
-        if let Some(c) = active_layer.data.get(cur_index) {
-            if *c != color_to_replace || res.contains(&cur_index) {
-                return;
-            }
-
-            res.push(cur_index);
-            // search right
-            if cur_index % self.num_pixels != self.num_pixels - 1 {
-                self.use_fill(res, color_to_replace, cur_index + 1);
-            }
-
-            // search left
-            if cur_index % self.num_pixels != 0 {
-                self.use_fill(res, color_to_replace, cur_index - 1);
+    /// Draws the canvas as two textures (the checkerboard background and the flattened layers),
+    /// followed by the preview of the tool in use. Only the pixels that changed since the last
+    /// frame are composited and uploaded again.
+    pub fn draw(&mut self, camera: &Camera2D) {
+        let size = vec2(self.width as f32 * RECT_DIMS.x, self.height as f32 * RECT_DIMS.y);
+        let params = DrawTextureParams { dest_size: Some(size), ..Default::default() };
+
+        if self.background.is_none() {
+            let background = Texture2D::from_image(&self.checkerboard_image());
+            background.set_filter(FilterMode::Nearest);
+            self.background = Some(background);
+        }
+        let whole = ((0, 0), (self.width - 1, self.height - 1));
+        let dirty = self.dirty.take().unwrap_or(whole);
+        match self.texture.take() {
+            Some((revision, texture)) if revision == self.revision => {
+                self.texture = Some((revision, texture));
             }
-
-            // search top
-            if cur_index >= self.num_pixels {
-                self.use_fill(res, color_to_replace, cur_index - self.num_pixels);
+            Some((_, texture)) => {
+                let ((x0, y0), (x1, y1)) = dirty;
+                let (w, h) = ((x1 - x0 + 1) as i32, (y1 - y0 + 1) as i32);
+                texture.update_part(&self.composite_part(dirty), x0 as i32, y0 as i32, w, h);
+                self.texture = Some((self.revision, texture));
             }
-
-            // search bottom
-            if cur_index <= self.num_pixels.pow(2) {
-                self.use_fill(res, color_to_replace, cur_index + self.num_pixels);
+            None => {
+                let texture = Texture2D::from_image(&self.composite_part(whole));
+                texture.set_filter(FilterMode::Nearest);
+                self.texture = Some((self.revision, texture));
             }
         }
-    }
-
-    pub fn draw(&self, camera: &Camera2D) {
-        let c1 = WHITE;
-        let c2 = BLACK;
 
-        let tool = &self.tool;
-        let kind = tool.kind;
-        let info = &tool.info;
-        let start_end_pos: Option<(Vec2, Vec2)> = self.get_pos_from_tool_info(camera, info);
-
-        for i in 0..(self.num_pixels * self.num_pixels) {
-            let active_layer = self.active_layer().unwrap();
-            let x = (i % self.num_pixels) as f32;
-            let y = (i / self.num_pixels) as f32;
+        // -1.0 to start from the top left of the screen
+        if let Some(background) = &self.background {
+            draw_texture_ex(background, -1.0, -1.0, WHITE, params.clone());
+        }
+        if let Some((_, texture)) = &self.texture {
+            draw_texture_ex(texture, -1.0, -1.0, WHITE, params);
+        }
 
-            // Draw the contents of all layers or else the bg
-            if let Some(c) = active_layer.data[i] {
-                // -1.0 to start from the top left of the screen
-                draw_rectangle(
-                    x * RECT_DIMS.x - 1.0,
-                    y * RECT_DIMS.y - 1.0,
-                    RECT_DIMS.x,
-                    RECT_DIMS.y,
-                    c,
-                );
-            } else {
-                'inner: {
-                    for (_id,l) in self.layers.iter() {
-                        if l.data[i].is_some() {
-                            draw_rectangle(
-                                x * RECT_DIMS.x - 1.0,
-                                y * RECT_DIMS.y - 1.0,
-                                RECT_DIMS.x,
-                                RECT_DIMS.y,
-                                l.data[i].unwrap(),
-                            );
-                            break 'inner;
-                        }
+        // Now draw any tool-specific things (example: rectangle borders for Rect tool)
+        let info = &self.tool.info;
+        match self.tool.kind {
+            ToolKind::Rect(fill_type) => {
+                let Some((min, max)) = self.get_pos_from_tool_info(camera, info) else { return };
+                let (w, h) = (max.x - min.x + RECT_DIMS.x, max.y - min.y + RECT_DIMS.y);
+                let c = self.selected_color;
+                match fill_type {
+                    FillType::SolidFill => draw_rectangle(min.x, min.y, w, h, c),
+                    FillType::NoFill => {
+                        draw_rectangle(min.x, min.y, w, RECT_DIMS.y, c);
+                        draw_rectangle(min.x, max.y, w, RECT_DIMS.y, c);
+                        draw_rectangle(min.x, min.y, RECT_DIMS.x, h, c);
+                        draw_rectangle(max.x, min.y, RECT_DIMS.x, h, c);
                     }
-
-                    let c = if (x as usize + y as usize) % 2 == 0 {
-                        c1
-                    } else {
-                        c2
-                    };
-                    draw_rectangle(
-                        x * RECT_DIMS.x - 1.0,
-                        y * RECT_DIMS.y - 1.0,
-                        RECT_DIMS.x,
-                        RECT_DIMS.y,
-                        c,
-                    );
                 }
             }
-
-            // Now draw any tool-specific things (example: rectangle borders for Rect tool)
-            match kind {
-                ToolKind::Rect(fill_type) => {
-                    if start_end_pos.is_none() {
-                        continue;
-                    }
-                    let (min, max) = start_end_pos.unwrap();
-                    let cpos = vec2(x * RECT_DIMS.x - 1.0, y * RECT_DIMS.y - 1.0);
-
-                    match fill_type {
-                        FillType::NoFill => {
-                            if !((cpos.x >= min.x && cpos.x <= max.x)
-                                && (cpos.y == min.y || cpos.y == max.y))
-                                && !((cpos.y >= min.y && cpos.y <= max.y)
-                                    && (cpos.x == min.x || cpos.x == max.x))
-                            {
-                                continue;
-                            }
-                        }
-
-                        FillType::SolidFill => {
-                            if !((cpos.x >= min.x && cpos.x <= max.x)
-                                && (cpos.y >= min.y && cpos.y <= max.y))
-                            {
-                                continue;
-                            }
-                        }
-                    }
-                    draw_rectangle(
-                        x * RECT_DIMS.x - 1.0,
-                        y * RECT_DIMS.y - 1.0,
-                        RECT_DIMS.x,
-                        RECT_DIMS.y,
-                        self.selected_color,
-                    );
-                }
-                ToolKind::Line => {
-                    if let Some(v) = info.pixel_indices.as_ref() {
-                        if v.contains(&i) {
-                            draw_rectangle(
-                                x * RECT_DIMS.x - 1.0,
-                                y * RECT_DIMS.y - 1.0,
-                                RECT_DIMS.x,
-                                RECT_DIMS.y,
-                                self.selected_color,
-                            );
-                        }
+            ToolKind::Line => {
+                for i in info.pixel_indices.iter().flatten() {
+                    if let Some(r) = self.get_pixel_rect_from_index(*i) {
+                        draw_rectangle(r.x, r.y, r.w, r.h, self.selected_color);
                     }
                 }
-                _ => {}
             }
+            _ => {}
         }
     }
 
+    /// White and black squares, one per pixel, shown behind transparent pixels.
+    fn checkerboard_image(&self) -> Image {
+        let mut img = Image::gen_image_color(self.width as u16, self.height as u16, BLACK);
+        for y in 0..self.height {
+            for x in (y % 2..self.width).step_by(2) {
+                img.set_pixel(x as u32, y as u32, WHITE);
+            }
+        }
+        img
+    }
+
     pub fn set_pixel_at_mouse_position_selected_color(&mut self, camera: &Camera2D) {
         self.set_pixel_at_mouse_position(Some(self.selected_color), camera);
     }
@@ -272,11 +262,10 @@ impl Canvas {
         let mp = camera.screen_to_world(mouse_position().into());
         let index = self.get_pixel_index_from_position(mp);
         let layer_id = self.active_layer;
-        let active_layer = self.active_layer_mut().unwrap();
 
         if let Some(index) = index {
-            let prev = active_layer.data[index];
-            active_layer.set_pixel(index, color);
+            let prev = self.active_layer().unwrap().data[index];
+            self.set_pixel_at(index, color);
             let dinfo = DrawInfo{to:color,from:prev};
             return Some(DrawPixelsInfo::new(vec![(index,dinfo)],layer_id));
         }
@@ -285,7 +274,10 @@ impl Canvas {
 
     pub fn set_pixel_at(&mut self, index: usize, color: Option<Color>) {
         let active_layer = self.active_layer_mut().unwrap();
-        active_layer.set_pixel(index, color);
+        if active_layer.data[index] != color {
+            active_layer.set_pixel(index, color);
+            self.pixels_changed([index]);
+        }
     }
 
     pub fn set_tool(&mut self, new_tool: Tool) {
@@ -303,10 +295,15 @@ impl Canvas {
     }
 
     pub fn use_tool_at_mouse_position(&mut self, camera: &Camera2D) -> Option<DrawPixelsInfo> {
-        let np = self.num_pixels;
+        let width = self.width;
         let sc = Some(self.selected_color);
         let layer_id = self.active_layer;
 
+        if self.active_layer().unwrap().locked {
+            self.tool.info = ToolInfo::default();
+            return None;
+        }
+
         match self.tool.kind {
             ToolKind::Pixel => {
                 if !is_mouse_button_down(MouseButton::Left) {
@@ -337,44 +334,26 @@ impl Canvas {
                     return None;
                 }
 
-                let rects = self.get_pos_from_tool_info(camera, &self.tool.info);
+                let corners = self.get_pixel_corners_from_tool_info(camera, &self.tool.info);
 
                 self.tool.info = ToolInfo::default();
 
-                if rects.is_none() {
-                    return None;
-                }
-                let (min, max) = rects.unwrap();
+                let ((x0, y0), (x1, y1)) = corners?;
                 let mut drawn_pixels = Vec::<(usize,DrawInfo)>::new();
 
-                for i in 0..(np.pow(2)) {
-                    let x = (i % np) as f32;
-                    let y = (i / np) as f32;
-
-                    let cpos = vec2(x * RECT_DIMS.x - 1.0, y * RECT_DIMS.y - 1.0);
-                    match fill_type {
-                        FillType::NoFill => {
-                            if !((cpos.x >= min.x && cpos.x <= max.x)
-                                && (cpos.y == min.y || cpos.y == max.y))
-                                && !((cpos.y >= min.y && cpos.y <= max.y)
-                                    && (cpos.x == min.x || cpos.x == max.x))
-                            {
-                                continue;
-                            }
-                        }
-                        FillType::SolidFill => {
-                            if !((cpos.x >= min.x && cpos.x <= max.x)
-                                && (cpos.y >= min.y && cpos.y <= max.y))
-                            {
-                                continue;
-                            }
+                for y in y0..=y1 {
+                    for x in x0..=x1 {
+                        let on_border = x == x0 || x == x1 || y == y0 || y == y1;
+                        if fill_type == FillType::NoFill && !on_border {
+                            continue;
                         }
-                    }
 
-                    let from = self.active_layer().unwrap().data[i];
-                    let dinfo = DrawInfo::new(from,sc);
-                    drawn_pixels.push((i,dinfo)); 
-                    self.set_pixel_at(i, sc);
+                        let i = x + y * width;
+                        let from = self.active_layer().unwrap().data[i];
+                        let dinfo = DrawInfo::new(from,sc);
+                        drawn_pixels.push((i,dinfo));
+                        self.set_pixel_at(i, sc);
+                    }
                 }
                 let dpi = DrawPixelsInfo::new(drawn_pixels, layer_id);
                 return Some(dpi);
@@ -428,35 +407,20 @@ impl Canvas {
         }
     }
 
-    // Currently the time complexity is O(n), but i think we can make it better by using just
-    // maths? maybe?
-    // Or we can make it O(nlogn) by using a binary search type thing
+    /// The index of the pixel under `pos` (in world coordinates), or None if `pos` is outside the
+    /// canvas. Pixels include their top and left edges but not their bottom and right ones.
     pub fn get_pixel_index_from_position(&self, pos: Vec2) -> Option<usize> {
-        let mp = Rect::new(pos.x, pos.y, 0.01, 0.01);
-
-        for i in 0..(self.num_pixels.pow(2)) {
-            let x = (i % self.num_pixels) as f32;
-            let y = (i / self.num_pixels) as f32;
-
-            let rect = Rect::new(
-                x * RECT_DIMS.x - 1.0,
-                y * RECT_DIMS.y - 1.0,
-                RECT_DIMS.x,
-                RECT_DIMS.y,
-            );
-            if rect.intersect(mp).is_some() {
-                return Some(i);
-            }
-        }
-        None
+        let x = pixel_coordinate(pos.x, RECT_DIMS.x, self.width)?;
+        let y = pixel_coordinate(pos.y, RECT_DIMS.y, self.height)?;
+        Some(x + y * self.width)
     }
 
     pub fn get_pixel_rect_from_index(&self, i: usize) -> Option<Rect> {
-        if i > self.num_pixels.pow(2) {
+        if i >= self.width * self.height {
             return None;
         }
-        let x = (i % self.num_pixels) as f32;
-        let y = (i / self.num_pixels) as f32;
+        let x = (i % self.width) as f32;
+        let y = (i / self.width) as f32;
         Some(Rect::new(
             x * RECT_DIMS.x - 1.0,
             y * RECT_DIMS.y - 1.0,
@@ -466,34 +430,27 @@ impl Canvas {
     }
 
     pub fn get_pixel_rect_from_position(&self, pos: Vec2) -> Option<Rect> {
-        // NOTE: almost the same code as get_pixel_index_from_position, now any optimizations would apply here
-        let p = Rect::new(pos.x, pos.y, 0.01, 0.01);
-
-        for i in 0..(self.num_pixels.pow(2)) {
-            let x = (i % self.num_pixels) as f32;
-            let y = (i / self.num_pixels) as f32;
-
-            let rect = Rect::new(
-                x * RECT_DIMS.x - 1.0,
-                y * RECT_DIMS.y - 1.0,
-                RECT_DIMS.x,
-                RECT_DIMS.y,
-            );
-            if rect.intersect(p).is_some() {
-                return Some(rect);
-            }
-        }
-        None
+        self.get_pixel_index_from_position(pos)
+            .and_then(|i| self.get_pixel_rect_from_index(i))
     }
 
     pub fn active_layer(&self) -> Option<&Layer> {
         self.layers.get(&self.active_layer)
     }
 
+    /// Changes made through this aren't noticed, call `layers_changed` after making any.
     pub fn active_layer_mut(&mut self) -> Option<&mut Layer> {
         self.layers.get_mut(&self.active_layer)
     }
 
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
     pub fn selected_color(&self) -> Color {
         self.selected_color
     }
@@ -501,32 +458,82 @@ impl Canvas {
         self.selected_color = nc;
     }
 
+    pub fn fill_options(&self) -> &FillOptions {
+        &self.fill_options
+    }
+    pub fn set_fill_options(&mut self, opts: FillOptions) {
+        self.fill_options = opts;
+    }
+
+    pub fn palette(&self) -> &[Color] {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Vec<Color>) {
+        self.palette = palette;
+    }
+
 
-    /// Creates an image based on all layers
+    /// Creates an image based on all layers. It is upside down, see `Layer::to_image`.
     pub fn to_image_all(&self) -> Image {
-       let mut img = Image::gen_image_color(self.num_pixels as u16,self.num_pixels as u16,Color::from_rgba(0,0,0,0)); 
-
-       let active_layer = self.active_layer().unwrap();
-       for i in 0..self.num_pixels.pow(2) {
-
-           let x = (i % self.num_pixels) as u32;
-           // We have to do this because otherwise the image is upside down
-           let y = (self.num_pixels - 1 - (i / self.num_pixels)) as u32;
-           if let Some(c) = active_layer.data[i] {
-                img.set_pixel(x,y,c);
-                continue;
-           }
-
-           for (_id,l) in self.layers.iter() {
-               if let Some(c) = l.data[i] {
-                   img.set_pixel(x,y,c);
-                   continue;
-               }
-           }
-       }
-       img
+        let mut bytes = vec![0; self.width * self.height * 4];
+        for i in 0..self.width * self.height {
+            let Some(c) = self.flattened_pixel(i) else { continue };
+
+            let x = i % self.width;
+            // Images for export_png have to be upside down
+            let y = self.height - 1 - i / self.width;
+            let offset = (x + y * self.width) * 4;
+            let rgba: [u8; 4] = c.into();
+            bytes[offset..offset + 4].copy_from_slice(&rgba);
+        }
+        Image { bytes, width: self.width as u16, height: self.height as u16 }
+    }
+
+    /// The flattened pixels from the top left to the bottom right corner (both included), the
+    /// right way up.
+    fn composite_part(&self, ((x0, y0), (x1, y1)): ((usize, usize), (usize, usize))) -> Image {
+        let (width, height) = (x1 - x0 + 1, y1 - y0 + 1);
+        let mut bytes = vec![0; width * height * 4];
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let Some(c) = self.flattened_pixel(x + y * self.width) else { continue };
+                let offset = ((x - x0) + (y - y0) * width) * 4;
+                let rgba: [u8; 4] = c.into();
+                bytes[offset..offset + 4].copy_from_slice(&rgba);
+            }
+        }
+        Image { bytes, width: width as u16, height: height as u16 }
     }
 
+    /// The color of pixel `i` as seen on the canvas, see `composite_pixel`.
+    pub fn flattened_pixel(&self, i: usize) -> Option<Color> {
+        composite_pixel(self.layers.values(), i)
+    }
+
+    /// Changes whenever any layer changes.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Tells the canvas that layers were changed through `get_layer_mut` or `active_layer_mut`,
+    /// so that everything is drawn again.
+    pub fn layers_changed(&mut self) {
+        self.revision += 1;
+        self.dirty = Some(((0, 0), (self.width - 1, self.height - 1)));
+    }
+
+    /// Like `layers_changed`, but only the pixels at `indices` are drawn again.
+    fn pixels_changed(&mut self, indices: impl IntoIterator<Item = usize>) {
+        for i in indices {
+            let (x, y) = (i % self.width, i / self.width);
+            self.dirty = Some(match self.dirty {
+                Some(((x0, y0), (x1, y1))) => ((x0.min(x), y0.min(y)), (x1.max(x), y1.max(y))),
+                None => ((x, y), (x, y)),
+            });
+            self.revision += 1;
+        }
+    }
 
     pub fn undo(&mut self, info:&DrawPixelsInfo) {
         let layer_id = info.layer_id;
@@ -534,6 +541,7 @@ impl Canvas {
             for (index,draw_info) in info.pixels.iter() {
                 layer.data[*index] = draw_info.from;
             }
+            self.pixels_changed(info.pixels.iter().map(|(index,_)| *index));
         }
     }
 
@@ -543,6 +551,7 @@ impl Canvas {
             for (index,draw_info) in info.pixels.iter() {
                 layer.data[*index] = draw_info.to;
             }
+            self.pixels_changed(info.pixels.iter().map(|(index,_)| *index));
         }
     }
 
@@ -550,6 +559,7 @@ impl Canvas {
         self.layers.get(&id)
     }
 
+    /// Changes made through this aren't noticed, call `layers_changed` after making any.
     pub fn get_layer_mut(&mut self,id:LayerId) -> Option<&mut Layer> {
         self.layers.get_mut(&id)
     }
@@ -571,14 +581,378 @@ impl Canvas {
         self.active_layer
     }
 
-    pub fn create_new_layer(&mut self)  {
-        let nl = Layer::new(self.num_pixels);
+    /// Appends a new, empty layer on top of the others.
+    pub fn create_new_layer(&mut self) -> LayerInfo {
+        let info = LayerInfo{
+            layer_id: LayerId::new_v4(),
+            index: self.layers.len(),
+            layer: Layer::named(self.width, self.height, self.unused_layer_name()),
+        };
+        self.insert_layer(&info);
+        info
+    }
+
+    /// "Layer N", with the lowest N (starting from the number of layers) no layer is named after.
+    fn unused_layer_name(&self) -> String {
+        (self.layers.len()..)
+            .map(|n| format!("Layer {n}"))
+            .find(|name| self.layers.values().all(|l| l.name != *name))
+            .unwrap()
+    }
+
+    /// Appends `layer` on top of the others and returns its new id.
+    pub fn add_layer(&mut self, layer:Layer) -> LayerId {
         let id = LayerId::new_v4();
-        self.layers.insert(id,nl);
+        self.layers.insert(id,layer);
+        self.layers_changed();
+        id
+    }
+
+    /// Puts a layer back at `info.index`, e.g. when redoing. Returns false (and does nothing) if
+    /// a layer with the same id is already there.
+    pub fn insert_layer(&mut self, info:&LayerInfo) -> bool {
+        if self.layers.contains_key(&info.layer_id) {
+            return false;
+        }
+        let index = info.index.min(self.layers.len());
+        self.layers.shift_insert(index,info.layer_id,info.layer.clone());
+        self.layers_changed();
+        true
+    }
+
+    /// Removes a layer, moving the active layer to a neighbour if it was the one removed. The
+    /// last remaining layer can't be removed.
+    pub fn remove_layer(&mut self, id:LayerId) -> Option<LayerInfo> {
+        if self.layers.len() <= 1 {
+            return None;
+        }
+        let (index,layer_id,layer) = self.layers.shift_remove_full(&id)?;
+        self.layers_changed();
+        if self.active_layer == id {
+            self.active_layer = *self.layers.get_index(index.saturating_sub(1)).unwrap().0;
+        }
+        Some(LayerInfo{ layer_id, index, layer })
+    }
+
+    /// Moves a layer to position `to`, shifting the layers in between.
+    pub fn move_layer(&mut self, id:LayerId, to:usize) -> Option<MoveLayerInfo> {
+        let from = self.layers.get_index_of(&id)?;
+        let to = to.min(self.layers.len() - 1);
+        if from == to {
+            return None;
+        }
+        self.layers.move_index(from,to);
+        self.layers_changed();
+        Some(MoveLayerInfo{ layer_id:id, from, to })
+    }
+
+    /// Puts a copy of a layer right above it and makes the copy the active layer.
+    pub fn duplicate_layer(&mut self, id:LayerId) -> Option<LayerInfo> {
+        let index = self.layers.get_index_of(&id)?;
+        let mut layer = self.layers[index].clone();
+        layer.name = format!("{} copy", layer.name);
+
+        let info = LayerInfo{ layer_id:LayerId::new_v4(), index:index + 1, layer };
+        self.insert_layer(&info);
+        self.active_layer = info.layer_id;
+        Some(info)
+    }
+
+    /// If `merge_down` can merge the layer, see there.
+    pub fn can_merge_down(&self, id:LayerId) -> bool {
+        match self.layers.get_index_of(&id) {
+            Some(index) if index > 0 => {
+                let (below, top) = (&self.layers[index - 1], &self.layers[index]);
+                below.visible
+                    && below.blend_mode == BlendMode::Normal
+                    && top.blend_mode == BlendMode::Normal
+            }
+            _ => false,
+        }
+    }
+
+    /// Blends a layer into the one below it, so that the result looks like the two did. Both have
+    /// to use the Normal blend mode and the lower one has to be visible: other modes depend on
+    /// the layers further down, which the merged layer can't keep. The result keeps the name and
+    /// lock of the lower layer and has full opacity, with the opacity of both baked into the
+    /// pixels. A hidden upper layer leaves nothing behind.
+    pub fn merge_down(&mut self, id:LayerId) -> Option<MergeLayersInfo> {
+        if !self.can_merge_down(id) {
+            return None;
+        }
+        let index = self.layers.get_index_of(&id)?;
+        let (&target_id, below) = self.layers.get_index(index - 1).unwrap();
+        let top = &self.layers[index];
+
+        let mut after = below.clone();
+        after.opacity = 1.0;
+        for i in 0..self.width * self.height {
+            after.data[i] = composite_pixel([below, top], i);
+        }
+
+        let info = MergeLayersInfo{
+            target_id,
+            before: below.clone(),
+            after,
+            removed: vec![LayerInfo{ layer_id:id, index, layer:top.clone() }],
+        };
+        self.redo_merge(&info);
+        Some(info)
+    }
+
+    /// Merges all layers into the bottom one, exactly as they are seen on the canvas.
+    pub fn flatten(&mut self) -> Option<MergeLayersInfo> {
+        if self.layers.len() <= 1 {
+            return None;
+        }
+        let (&target_id, bottom) = self.layers.get_index(0).unwrap();
+
+        let mut after = Layer::named(self.width, self.height, bottom.name.clone());
+        for i in 0..self.width * self.height {
+            after.data[i] = self.flattened_pixel(i);
+        }
+
+        let removed = self.layers.iter().enumerate().skip(1)
+            .map(|(index,(&layer_id,layer))| LayerInfo{ layer_id, index, layer:layer.clone() })
+            .collect();
+        let info = MergeLayersInfo{ target_id, before: bottom.clone(), after, removed };
+        self.redo_merge(&info);
+        Some(info)
+    }
+
+    /// Does a merge (again), e.g. when redoing.
+    pub fn redo_merge(&mut self, info:&MergeLayersInfo) {
+        for removed in info.removed.iter() {
+            self.layers.shift_remove(&removed.layer_id);
+        }
+        if let Some(target) = self.layers.get_mut(&info.target_id) {
+            *target = info.after.clone();
+        }
+        if !self.layers.contains_key(&self.active_layer) {
+            self.active_layer = info.target_id;
+        }
+        self.layers_changed();
+    }
+
+    pub fn undo_merge(&mut self, info:&MergeLayersInfo) {
+        if let Some(target) = self.layers.get_mut(&info.target_id) {
+            *target = info.before.clone();
+        }
+        // In ascending order, so every layer ends up at its old index
+        for removed in info.removed.iter() {
+            self.insert_layer(removed);
+        }
+        self.layers_changed();
+    }
+
+}
+
+/// The column (or row) of pixels `pos` is in, along one axis with `count` pixels of `size`. The
+/// edges are the same as in `get_pixel_rect_from_index`, where rounding can put them slightly off
+/// from a plain division.
+fn pixel_coordinate(pos: f32, size: f32, count: usize) -> Option<usize> {
+    // -1.0 because the canvas starts from the top left of the screen
+    let edge = |n: f32| n * size - 1.0;
+    let mut n = ((pos + 1.0) / size).floor();
+    if edge(n + 1.0) <= pos {
+        n += 1.0;
+    } else if edge(n) > pos {
+        n -= 1.0;
+    }
+    // Also rejects NaN
+    if !(n >= 0.0 && n < count as f32) {
+        return None;
+    }
+    Some(n as usize)
+}
+
+/// The color of pixel `i` with every visible layer of `layers` (bottom first) drawn over the ones
+/// below it, with its blend mode and opacity. None if the result is fully transparent.
+fn composite_pixel<'a>(layers: impl IntoIterator<Item = &'a Layer>, i: usize) -> Option<Color> {
+    let mut out: Option<Color> = None;
+    for layer in layers.into_iter().filter(|l| l.visible) {
+        if let Some(c) = layer.data[i] {
+            out = Some(blend::composite(out, c, layer.blend_mode, layer.opacity));
+        }
+    }
+    out.filter(|c| c.a > 0.0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::undo_redo::Action;
+
+    /// A canvas with `n` one pixel wide layers, named "Layer 0" (bottom) to "Layer n-1", each with
+    /// a pixel of its own color.
+    fn canvas(n: usize) -> Canvas {
+        let mut canvas = Canvas::new(2, 1);
+        for _ in 1..n {
+            canvas.create_new_layer();
+        }
+        let ids = canvas.layers().keys().copied().collect::<Vec<_>>();
+        for (i, id) in ids.into_iter().enumerate() {
+            let c = Color::from_rgba(50 * i as u8, 0, 0, 255);
+            canvas.get_layer_mut(id).unwrap().set_pixel_color(i % 2, c);
+        }
+        canvas
+    }
+
+    type Snapshot = Vec<(LayerId, String, Vec<Option<[u8; 4]>>)>;
+
+    /// Everything the layer operations can change, bottom layer first.
+    fn snapshot(canvas: &Canvas) -> Snapshot {
+        let pixels = |l: &Layer| l.data.iter().map(|c| c.map(|c| c.into())).collect();
+        canvas.layers().iter().map(|(id, l)| (*id, l.name.clone(), pixels(l))).collect()
+    }
+
+    /// Pixel `i` of the layer at `index` in a snapshot.
+    fn snapshot_pixel(snapshot: &Snapshot, index: usize, i: usize) -> Option<Color> {
+        snapshot[index].2[i].map(|[r, g, b, a]| Color::from_rgba(r, g, b, a))
     }
 
-    pub fn delete_layer(&mut self, id:LayerId)  {
-        self.layers.shift_remove(&id);
+    /// Like `Project::undo`.
+    fn undo(canvas: &mut Canvas, action: &Action) {
+        match action {
+            Action::AddLayer(info) => assert!(canvas.remove_layer(info.layer_id).is_some()),
+            Action::RemoveLayer(info) => assert!(canvas.insert_layer(info)),
+            Action::MoveLayer(info) => {
+                assert!(canvas.move_layer(info.layer_id, info.from).is_some());
+            }
+            Action::MergeLayers(info) => canvas.undo_merge(info),
+            Action::DrawPixels(info) => canvas.undo(info),
+        }
     }
 
+    /// Like `Project::redo`.
+    fn redo(canvas: &mut Canvas, action: &Action) {
+        match action {
+            Action::AddLayer(info) => assert!(canvas.insert_layer(info)),
+            Action::RemoveLayer(info) => assert!(canvas.remove_layer(info.layer_id).is_some()),
+            Action::MoveLayer(info) => assert!(canvas.move_layer(info.layer_id, info.to).is_some()),
+            Action::MergeLayers(info) => canvas.redo_merge(info),
+            Action::DrawPixels(info) => canvas.redo(info),
+        }
+    }
+
+    #[test]
+    fn pixel_index_from_position() {
+        let canvas = Canvas::new(3, 2);
+        // Measured in pixels from the top left corner of the canvas
+        let at = |x: f32, y: f32| vec2(-1.0 + x * RECT_DIMS.x, -1.0 + y * RECT_DIMS.y);
+        let index = |pos| canvas.get_pixel_index_from_position(pos);
+
+        assert_eq!(index(at(0.5, 0.5)), Some(0));
+        assert_eq!(index(at(2.5, 0.5)), Some(2));
+        assert_eq!(index(at(1.5, 1.5)), Some(4));
+
+        // Top and left edges belong to the pixel, bottom and right ones to the next
+        assert_eq!(index(at(0.0, 0.0)), Some(0));
+        let wide = Canvas::new(40, 25);
+        for i in 0..40 * 25 {
+            let r = wide.get_pixel_rect_from_index(i).unwrap();
+            assert_eq!(wide.get_pixel_index_from_position(vec2(r.x, r.y)), Some(i));
+            let inside = vec2(r.x + r.w * 0.99, r.y + r.h * 0.99);
+            assert_eq!(wide.get_pixel_index_from_position(inside), Some(i));
+        }
+        assert_eq!(index(at(2.99, 0.5)), Some(2));
+        assert_eq!(index(at(3.01, 0.5)), None);
+        assert_eq!(index(at(0.5, 1.99)), Some(3));
+        assert_eq!(index(at(0.5, 2.01)), None);
+
+        assert_eq!(index(at(-0.01, 0.5)), None);
+        assert_eq!(index(at(0.5, -0.01)), None);
+        assert_eq!(index(at(-5.0, -5.0)), None);
+        assert_eq!(index(vec2(f32::NAN, -1.0)), None);
+        assert_eq!(index(vec2(-1.0, f32::NAN)), None);
+        assert_eq!(index(vec2(f32::INFINITY, -1.0)), None);
+    }
+
+    #[test]
+    fn undo_and_redo_restore_layers() {
+        type Op = fn(&mut Canvas, LayerId) -> Option<Action>;
+        let ops: [(&str, Op); 5] = [
+            ("remove", |c, id| c.remove_layer(id).map(Action::RemoveLayer)),
+            ("move", |c, id| c.move_layer(id, 0).map(Action::MoveLayer)),
+            ("duplicate", |c, id| c.duplicate_layer(id).map(Action::AddLayer)),
+            ("merge down", |c, id| c.merge_down(id).map(Action::MergeLayers)),
+            ("flatten", |c, _| c.flatten().map(Action::MergeLayers)),
+        ];
+        for (name, op) in ops {
+            let mut canvas = canvas(4);
+            let id = *canvas.layers().get_index(2).unwrap().0;
+            let before = snapshot(&canvas);
+
+            let action = op(&mut canvas, id).unwrap();
+            let after = snapshot(&canvas);
+            assert_ne!(after, before, "{name}");
+
+            undo(&mut canvas, &action);
+            assert_eq!(snapshot(&canvas), before, "undo {name}");
+            redo(&mut canvas, &action);
+            assert_eq!(snapshot(&canvas), after, "redo {name}");
+        }
+    }
+
+    #[test]
+    fn undo_flatten_restores_indices() {
+        let mut canvas = canvas(3);
+        let before = snapshot(&canvas);
+        let Some(info) = canvas.flatten() else { panic!("nothing flattened") };
+
+        assert_eq!(canvas.layers().len(), 1);
+        assert_eq!(canvas.active_layer_id(), before[0].0);
+        let flat = canvas.active_layer().unwrap();
+        assert_eq!(flat.data, vec![snapshot_pixel(&before, 2, 0), snapshot_pixel(&before, 1, 1)]);
+
+        canvas.undo_merge(&info);
+        assert_eq!(snapshot(&canvas), before);
+    }
+
+    #[test]
+    fn removing_the_active_layer_activates_a_neighbour() {
+        let mut canvas = canvas(3);
+        let ids = canvas.layers().keys().copied().collect::<Vec<_>>();
+
+        canvas.set_active_layer_as(ids[1]);
+        canvas.remove_layer(ids[1]).unwrap();
+        assert_eq!(canvas.active_layer_id(), ids[0]);
+
+        // The bottom layer has nothing below it, so the one above takes over
+        canvas.remove_layer(ids[0]).unwrap();
+        assert_eq!(canvas.active_layer_id(), ids[2]);
+
+        assert!(canvas.remove_layer(ids[2]).is_none(), "removed the last layer");
+    }
+
+    #[test]
+    fn merge_down_needs_normal_layers() {
+        let mut canvas = canvas(3);
+        let ids = canvas.layers().keys().copied().collect::<Vec<_>>();
+        assert!(!canvas.can_merge_down(ids[0]));
+        assert!(canvas.can_merge_down(ids[2]));
+
+        canvas.get_layer_mut(ids[2]).unwrap().blend_mode = BlendMode::Multiply;
+        assert!(!canvas.can_merge_down(ids[2]));
+        assert!(canvas.merge_down(ids[2]).is_none());
+
+        canvas.get_layer_mut(ids[2]).unwrap().blend_mode = BlendMode::Normal;
+        canvas.get_layer_mut(ids[1]).unwrap().visible = false;
+        assert!(!canvas.can_merge_down(ids[2]));
+    }
+
+    #[test]
+    fn merge_down_keeps_the_look() {
+        let mut canvas = canvas(3);
+        let ids = canvas.layers().keys().copied().collect::<Vec<_>>();
+        canvas.get_layer_mut(ids[2]).unwrap().opacity = 0.5;
+        let seen = (0..2).map(|i| canvas.flattened_pixel(i)).collect::<Vec<_>>();
+
+        canvas.merge_down(ids[2]).unwrap();
+        assert_eq!(canvas.layers().len(), 2);
+        let merged = canvas.get_layer(ids[1]).unwrap();
+        assert_eq!((merged.name.as_str(), merged.opacity), ("Layer 1", 1.0));
+        assert_eq!((0..2).map(|i| canvas.flattened_pixel(i)).collect::<Vec<_>>(), seen);
+    }
 }
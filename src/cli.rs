@@ -0,0 +1,254 @@
+/// The command line interface, for using pixeditor from build scripts. Nothing here opens a window
+/// or needs a GPU.
+///
+/// ```text
+/// pixeditor export in.png -o out.png --scale 4 --layer Body
+/// pixeditor batch sprites/ -o out/ --palette team.gpl --trim --format png --dry-run
+/// ```
+use std::path::Path;
+
+use crate::batch::{self, BatchOptions};
+use crate::canvas::{Canvas, LayerId};
+use crate::image_io;
+use crate::palette::{self, PaletteFormat};
+use crate::project::{self, SaveOptions};
+use crate::Layer;
+
+use indexmap::IndexMap;
+use macroquad::prelude::Color;
+
+const USAGE: &str = "\
+Usage:
+  pixeditor                       Open the editor
+  pixeditor export <INPUT> -o <OUTPUT> [OPTIONS]
+                                  Flatten INPUT and write it to OUTPUT
+  pixeditor batch <INPUT_DIR> -o <OUTPUT_DIR> [OPTIONS]
+                                  Process every file below INPUT_DIR into OUTPUT_DIR
+  pixeditor help                  Show this message
+
+INPUT can be a pixeditor project, an Aseprite or OpenRaster file or an image. The extension of
+OUTPUT picks the format, the same ones the editor can save to.
+
+Export options:
+  -o, --output <PATH>   Where to write the result
+  --scale <N>           Integer upscale factor for images (1 to 32, default 1)
+  --layer <NAME>        Only export this layer, by name or index. Can be given more than once
+  --embed-layers        Keep the layers inside PNG output
+  --quality <N>         JPEG quality (1 to 100, default 90)
+
+Batch options:
+  -o, --output <DIR>    Where to write the results, keeping the directory structure
+  --scale <N>           Integer upscale factor for images (1 to 32, default 1)
+  --palette <FILE>      Replace every color with the closest one of a palette file
+  --trim                Crop images to their non-transparent pixels
+  --format <EXT>        Convert everything to this format, e.g. `png` or `pxe`
+  --dry-run             Only report what would be done
+";
+
+/// Runs the command in `args` (without the program name) and returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let Some((command, rest)) = args.split_first() else {
+        eprint!("{USAGE}");
+        return 2;
+    };
+
+    let res = match command.as_str() {
+        "export" => export(rest),
+        "batch" => run_batch(rest),
+        "help" | "-h" | "--help" => {
+            print!("{USAGE}");
+            return 0;
+        }
+        other => Err(CliError::Usage(format!("unknown command `{other}`"))),
+    };
+
+    match res {
+        Ok(()) => 0,
+        Err(CliError::Usage(msg)) => {
+            eprintln!("error: {msg}\n\n{USAGE}");
+            2
+        }
+        Err(CliError::Failed(msg)) => {
+            eprintln!("error: {msg}");
+            1
+        }
+    }
+}
+
+enum CliError {
+    /// The arguments were wrong.
+    Usage(String),
+    /// The arguments were fine but the command didn't work out.
+    Failed(String),
+}
+
+fn export(args: &[String]) -> Result<(), CliError> {
+    let mut input = None;
+    let mut output = None;
+    let mut layers = Vec::new();
+    let mut opts = SaveOptions::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| CliError::Usage(format!("`{name}` needs a value")))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(arg)?.clone()),
+            "--scale" => opts.set_scale(parse_scale(arg, value(arg)?)?),
+            "--layer" => layers.push(value(arg)?.clone()),
+            "--embed-layers" => opts.set_embed_layers(true),
+            "--quality" => opts.set_jpeg_quality(parse_num(arg, value(arg)?)?),
+            flag if flag.starts_with('-') => {
+                return Err(CliError::Usage(format!("unknown option `{flag}`")));
+            }
+            path if input.is_none() => input = Some(path.to_string()),
+            extra => return Err(CliError::Usage(format!("unexpected argument `{extra}`"))),
+        }
+    }
+    let input = input.ok_or_else(|| CliError::Usage("missing input file".to_string()))?;
+    let output = output.ok_or_else(|| CliError::Usage("missing output file (-o)".to_string()))?;
+
+    let (project_name, import) =
+        project::load(&input).map_err(|e| CliError::Failed(format!("could not open {input}: {e}")))?;
+    for warning in import.warnings.iter() {
+        eprintln!("warning: {warning}");
+    }
+
+    let mut canvas = import.canvas;
+    if !layers.is_empty() {
+        canvas = select_layers(&canvas, &layers)?;
+    }
+    project::save_canvas(&output, &project_name, &canvas, &opts)
+        .map_err(|e| CliError::Failed(format!("could not write {output}: {e}")))
+}
+
+fn run_batch(args: &[String]) -> Result<(), CliError> {
+    let mut input = None;
+    let mut output = None;
+    let mut opts = BatchOptions { scale: 1, ..Default::default() };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| CliError::Usage(format!("`{name}` needs a value")))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(arg)?.clone()),
+            "--scale" => opts.scale = parse_scale(arg, value(arg)?)?,
+            "--palette" => opts.palette = Some(load_palette(value(arg)?)?),
+            "--trim" => opts.trim = true,
+            "--format" => {
+                let ext = value(arg)?.trim_start_matches('.');
+                if !project::is_save_path(&format!("file.{ext}")) {
+                    return Err(CliError::Usage(format!("unknown format `{ext}`")));
+                }
+                opts.format = Some(ext.to_string());
+            }
+            "--dry-run" => opts.dry_run = true,
+            flag if flag.starts_with('-') => {
+                return Err(CliError::Usage(format!("unknown option `{flag}`")));
+            }
+            path if input.is_none() => input = Some(path.to_string()),
+            extra => return Err(CliError::Usage(format!("unexpected argument `{extra}`"))),
+        }
+    }
+    let input = input.ok_or_else(|| CliError::Usage("missing input directory".to_string()))?;
+    let output =
+        output.ok_or_else(|| CliError::Usage("missing output directory (-o)".to_string()))?;
+
+    let entries = batch::run(Path::new(&input), Path::new(&output), &opts)
+        .map_err(|e| CliError::Failed(format!("could not read {input}: {e}")))?;
+
+    let (mut changed, mut failed) = (0, 0);
+    for entry in entries.iter() {
+        let input = entry.input.display();
+        let output = entry.output.display();
+        match &entry.result {
+            Ok(changes) if changes.is_empty() => println!("{input} -> {output}: unchanged"),
+            Ok(changes) => {
+                changed += 1;
+                println!("{input} -> {output}: {}", changes.join(", "));
+            }
+            Err(e) => {
+                failed += 1;
+                println!("{input}: FAILED: {e}");
+            }
+        }
+    }
+
+    let unchanged = entries.len() - changed - failed;
+    println!(
+        "\n{} files: {changed} changed, {unchanged} unchanged, {failed} failed",
+        entries.len()
+    );
+    if opts.dry_run {
+        println!("Dry run, nothing was written.");
+    }
+    if failed > 0 {
+        return Err(CliError::Failed(format!("{failed} files could not be processed")));
+    }
+    Ok(())
+}
+
+fn load_palette(path: &str) -> Result<Vec<Color>, CliError> {
+    let format = PaletteFormat::from_path(path)
+        .ok_or_else(|| CliError::Usage(format!("unknown palette format `{path}`")))?;
+    let colors = std::fs::File::open(path)
+        .map(std::io::BufReader::new)
+        .and_then(|mut r| palette::read(&mut r, format))
+        .map_err(|e| CliError::Failed(format!("could not open {path}: {e}")))?;
+    if colors.is_empty() {
+        return Err(CliError::Failed(format!("the palette {path} has no colors")));
+    }
+    Ok(colors)
+}
+
+/// A canvas with only the layers named in `names`, in their original order.
+fn select_layers(canvas: &Canvas, names: &[String]) -> Result<Canvas, CliError> {
+    let layers = canvas.layers();
+    let mut selected = vec![false; layers.len()];
+    for name in names {
+        let index = (0..layers.len())
+            .find(|&i| layers[i].name == *name || i.to_string() == *name)
+            .ok_or_else(|| {
+                let names = layers.values().map(|l| l.name.as_str()).collect::<Vec<_>>();
+                CliError::Failed(format!(
+                    "no layer `{name}`, the layers are: {}",
+                    names.join(", ")
+                ))
+            })?;
+        selected[index] = true;
+    }
+
+    let layers = layers
+        .iter()
+        .zip(selected)
+        .filter(|(_, selected)| *selected)
+        .map(|((id, layer), _)| (*id, layer.clone()))
+        .collect::<IndexMap<LayerId, Layer>>();
+    let top = *layers.last().unwrap().0;
+    let mut selection =
+        Canvas::from_layers(canvas.width(), canvas.height(), layers, top, canvas.selected_color());
+    selection.set_palette(canvas.palette().to_vec());
+    Ok(selection)
+}
+
+fn parse_num<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, CliError> {
+    value
+        .parse()
+        .map_err(|_| CliError::Usage(format!("invalid value `{value}` for `{name}`")))
+}
+
+fn parse_scale(name: &str, value: &str) -> Result<u32, CliError> {
+    let scale = parse_num(name, value)?;
+    if !(1..=image_io::MAX_EXPORT_SCALE).contains(&scale) {
+        return Err(CliError::Usage(format!(
+            "`{name}` must be between 1 and {}",
+            image_io::MAX_EXPORT_SCALE
+        )));
+    }
+    Ok(scale)
+}
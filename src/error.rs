@@ -0,0 +1,77 @@
+/// Errors that can happen while saving, loading or exporting a project.
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    Dialog(native_dialog::Error),
+    Archive(zip::result::ZipError),
+    /// We store paths as `String`s, so paths which aren't valid UTF-8 can't be used.
+    NonUtf8Path(PathBuf),
+}
+
+pub type ProjectResult<T> = Result<T, ProjectError>;
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectError::Io(e) => write!(f, "{e}"),
+            ProjectError::Image(e) => write!(f, "{e}"),
+            ProjectError::Dialog(e) => write!(f, "Could not open the file dialog: {e}"),
+            ProjectError::Archive(e) => write!(f, "{e}"),
+            ProjectError::NonUtf8Path(p) => {
+                write!(f, "The path {} is not valid UTF-8", p.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProjectError::Io(e) => Some(e),
+            ProjectError::Image(e) => Some(e),
+            ProjectError::Dialog(e) => Some(e),
+            ProjectError::Archive(e) => Some(e),
+            ProjectError::NonUtf8Path(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProjectError {
+    fn from(e: std::io::Error) -> Self {
+        ProjectError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for ProjectError {
+    fn from(e: image::ImageError) -> Self {
+        ProjectError::Image(e)
+    }
+}
+
+impl From<native_dialog::Error> for ProjectError {
+    fn from(e: native_dialog::Error) -> Self {
+        ProjectError::Dialog(e)
+    }
+}
+
+impl From<zip::result::ZipError> for ProjectError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ProjectError::Archive(e)
+    }
+}
+
+/// Converts a path returned by a file dialog into the `String` form we store.
+pub fn path_to_string(path: PathBuf) -> ProjectResult<String> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|p| ProjectError::NonUtf8Path(p.into()))
+}
+
+/// An `io::Error` for files whose contents are broken or not what we expect.
+pub fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
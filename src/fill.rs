@@ -0,0 +1,174 @@
+/// The flood fill behind the Fill tool. It works on pixel indices and a function that tells the
+/// color of a pixel, so it doesn't care whether that color comes from one layer or all of them.
+use macroquad::prelude::Color;
+
+/// Which neighbours of a pixel count as connected to it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    /// Left, right, up and down.
+    #[default]
+    Four,
+    /// Also the diagonals.
+    Eight,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FillOptions {
+    pub connectivity: Connectivity,
+    /// How far each RGBA channel (0 to 255) may be from the clicked color and still be filled.
+    pub tolerance: u8,
+    /// Fill every matching pixel of the layer, connected or not.
+    pub global: bool,
+    /// Match against what is seen on the canvas rather than only the active layer. The fill still
+    /// only draws on the active layer.
+    pub sample_all_layers: bool,
+}
+
+/// Returns the indices of the pixels to fill when clicking at `start`. `sample` gives the color
+/// of a pixel (None if transparent) and is only called with indices below `width * height`.
+pub fn flood_fill(
+    width: usize,
+    height: usize,
+    start: usize,
+    opts: &FillOptions,
+    sample: impl Fn(usize) -> Option<Color>,
+) -> Vec<usize> {
+    if start >= width * height {
+        return Vec::new();
+    }
+    let target = rgba(sample(start));
+    let similar = |i: usize| {
+        let c = rgba(sample(i));
+        (0..4).all(|ch| c[ch].abs_diff(target[ch]) <= opts.tolerance)
+    };
+
+    if opts.global {
+        return (0..width * height).filter(|&i| similar(i)).collect();
+    }
+
+    let mut out = Vec::new();
+    let mut visited = BitSet::new(width * height);
+    let fillable = |visited: &BitSet, x: usize, y: usize| {
+        let i = x + y * width;
+        !visited.contains(i) && similar(i)
+    };
+
+    // Every entry is a pixel of a run that wasn't filled yet. Popping it fills the whole run
+    // (the matching pixels left and right of it on the same row) and queues the runs above and
+    // below that touch it.
+    let mut stack = vec![(start % width, start / width)];
+    while let Some((x, y)) = stack.pop() {
+        if !fillable(&visited, x, y) {
+            continue;
+        }
+
+        let mut x0 = x;
+        while x0 > 0 && fillable(&visited, x0 - 1, y) {
+            x0 -= 1;
+        }
+        let mut x1 = x;
+        while x1 + 1 < width && fillable(&visited, x1 + 1, y) {
+            x1 += 1;
+        }
+        for xi in x0..=x1 {
+            visited.insert(xi + y * width);
+            out.push(xi + y * width);
+        }
+
+        // Diagonal neighbours touch the run from one pixel further out on each side
+        let (lo, hi) = match opts.connectivity {
+            Connectivity::Four => (x0, x1),
+            Connectivity::Eight => (x0.saturating_sub(1), (x1 + 1).min(width - 1)),
+        };
+        let rows = [y.checked_sub(1), Some(y + 1).filter(|&ny| ny < height)];
+        for ny in rows.into_iter().flatten() {
+            let mut in_run = false;
+            for xi in lo..=hi {
+                if fillable(&visited, xi, ny) {
+                    if !in_run {
+                        stack.push((xi, ny));
+                    }
+                    in_run = true;
+                } else {
+                    in_run = false;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Transparent pixels compare as transparent black.
+fn rgba(c: Option<Color>) -> [u8; 4] {
+    c.map_or([0; 4], |c| c.into())
+}
+
+struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    fn new(len: usize) -> Self {
+        Self { words: vec![0; len.div_ceil(64)] }
+    }
+
+    fn contains(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn insert(&mut self, i: usize) {
+        self.words[i / 64] |= 1 << (i % 64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::prelude::BLACK;
+
+    /// `#` is black, anything else transparent.
+    fn fill(rows: &[&str], start: usize, opts: &FillOptions) -> Vec<usize> {
+        let width = rows[0].len();
+        let pixels = rows.concat().chars().map(|c| (c == '#').then_some(BLACK)).collect::<Vec<_>>();
+        let mut filled = flood_fill(width, rows.len(), start, opts, |i| pixels[i]);
+        filled.sort();
+        filled
+    }
+
+    #[test]
+    fn connectivity() {
+        let rows = ["#..", ".#.", "..#"];
+        let four = FillOptions::default();
+        assert_eq!(fill(&rows, 0, &four), vec![0]);
+
+        let eight = FillOptions { connectivity: Connectivity::Eight, ..Default::default() };
+        assert_eq!(fill(&rows, 0, &eight), vec![0, 4, 8]);
+        // The empty pixels on both sides of the diagonal are only connected diagonally
+        assert_eq!(fill(&rows, 1, &four), vec![1, 2, 5]);
+        assert_eq!(fill(&rows, 1, &eight), vec![1, 2, 3, 5, 6, 7]);
+    }
+
+    #[test]
+    fn fills_around_corners() {
+        let rows = ["....", ".##.", ".#..", ".#.#"];
+        let filled = fill(&rows, 0, &FillOptions::default());
+        assert_eq!(filled, vec![0, 1, 2, 3, 4, 7, 8, 10, 11, 12, 14]);
+    }
+
+    #[test]
+    fn global_and_tolerance() {
+        let rows = ["#.#", "###"];
+        let global = FillOptions { global: true, ..Default::default() };
+        assert_eq!(fill(&rows, 1, &global), vec![1]);
+        assert_eq!(fill(&rows, 0, &global), vec![0, 2, 3, 4, 5]);
+
+        // Black and transparent differ by 255 in alpha
+        let tolerant = FillOptions { tolerance: 255, ..Default::default() };
+        assert_eq!(fill(&rows, 1, &tolerant), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn outside_the_canvas() {
+        assert!(fill(&["##"], 2, &FillOptions::default()).is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+/// Conversions between the `image` crate and our own canvas/layer types.
+use crate::canvas::{Canvas, LayerId};
+use crate::{Layer, MAX_PIXELS};
+
+use image::{ImageError, ImageResult, RgbaImage};
+use indexmap::IndexMap;
+use macroquad::prelude::*;
+
+/// Extensions of the image formats that can be opened as a project.
+pub const OPEN_IMAGE_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "bmp"];
+
+/// A canvas read from a layered file format that can hold more than we can represent.
+pub struct ImportedCanvas {
+    pub canvas: Canvas,
+    /// Human readable notes about everything that was skipped.
+    pub warnings: Vec<String>,
+}
+
+/// Opens an image file and turns it into a single layered canvas.
+pub fn load_canvas(path: &str) -> ImageResult<Canvas> {
+    let img = image::open(path)?.into_rgba8();
+    canvas_from_image(&img)
+}
+
+/// The canvas has the same size as the image.
+pub fn canvas_from_image(img: &RgbaImage) -> ImageResult<Canvas> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    if !(1..=MAX_PIXELS).contains(&width) || !(1..=MAX_PIXELS).contains(&height) {
+        return Err(ImageError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("image must be between 1 and {MAX_PIXELS} pixels wide and high"),
+        )));
+    }
+
+    let mut layer = layer_from_image(img, width, height);
+    layer.name = "Layer 0".to_string();
+    let id = LayerId::new_v4();
+    let mut layers = IndexMap::new();
+    layers.insert(id, layer);
+    Ok(Canvas::from_layers(width, height, layers, id, RED))
+}
+
+/// Fully transparent pixels become empty (`None`) pixels. Parts of the image that don't fit
+/// inside the layer are ignored.
+pub fn layer_from_image(img: &RgbaImage, width: usize, height: usize) -> Layer {
+    layer_from_image_at(img, width, height, (0, 0))
+}
+
+/// Same as `layer_from_image`, but with the top-left of the image at `offset` (which may be
+/// negative or outside the layer).
+pub fn layer_from_image_at(
+    img: &RgbaImage,
+    width: usize,
+    height: usize,
+    offset: (i64, i64),
+) -> Layer {
+    let mut layer = Layer::new(width, height);
+    for (x, y, p) in img.enumerate_pixels() {
+        let x = x as i64 + offset.0;
+        let y = y as i64 + offset.1;
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 || p[3] == 0 {
+            continue;
+        }
+        let index = x as usize + y as usize * width;
+        layer.set_pixel_color(index, Color::from_rgba(p[0], p[1], p[2], p[3]));
+    }
+    layer
+}
+
+/// Where an imported image is placed on the layer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImportPlacement {
+    #[default]
+    TopLeft,
+    Centered,
+    /// Top-left of the image at `ImportOptions::offset`.
+    Offset,
+}
+
+/// What to do with an imported image whose size doesn't match the canvas.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFit {
+    /// Keep the size, cutting off whatever is outside the canvas.
+    #[default]
+    Crop,
+    /// Scale (nearest-neighbour, keeping the aspect ratio) so that the image just fits.
+    Scale,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportOptions {
+    pub placement: ImportPlacement,
+    pub offset: (i64, i64),
+    pub fit: ImportFit,
+}
+
+/// Turns an image into a layer of a `width` by `height` canvas according to `opts`.
+pub fn import_layer(img: &RgbaImage, width: usize, height: usize, opts: &ImportOptions) -> Layer {
+    let scaled;
+    let img = match opts.fit {
+        ImportFit::Scale if img.width() > 0 && img.height() > 0 => {
+            let (cw, ch) = (width as u64, height as u64);
+            let (w, h) = (img.width() as u64, img.height() as u64);
+            // Largest size with the same aspect ratio that fits in the canvas
+            let (sw, sh) = if w * ch >= h * cw {
+                (cw, (h * cw / w).max(1))
+            } else {
+                ((w * ch / h).max(1), ch)
+            };
+            scaled = image::imageops::resize(
+                img,
+                sw as u32,
+                sh as u32,
+                image::imageops::FilterType::Nearest,
+            );
+            &scaled
+        }
+        _ => img,
+    };
+
+    let offset = match opts.placement {
+        ImportPlacement::TopLeft => (0, 0),
+        ImportPlacement::Centered => (
+            (width as i64 - img.width() as i64) / 2,
+            (height as i64 - img.height() as i64) / 2,
+        ),
+        ImportPlacement::Offset => opts.offset,
+    };
+    layer_from_image_at(img, width, height, offset)
+}
+
+/// Formats that a project can be exported (flattened) to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Png,
+    Bmp,
+    Tga,
+    Gif,
+    WebP,
+    Ico,
+    Jpeg,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 7] = [
+        ExportFormat::Png,
+        ExportFormat::Bmp,
+        ExportFormat::Tga,
+        ExportFormat::Gif,
+        ExportFormat::WebP,
+        ExportFormat::Ico,
+        ExportFormat::Jpeg,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "PNG Image",
+            ExportFormat::Bmp => "BMP Image",
+            ExportFormat::Tga => "TGA Image",
+            ExportFormat::Gif => "GIF Image",
+            ExportFormat::WebP => "WebP Image (lossless)",
+            ExportFormat::Ico => "ICO Icon",
+            ExportFormat::Jpeg => "JPEG Image",
+        }
+    }
+
+    /// The first extension is the one appended to file names without an extension.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            ExportFormat::Png => &["png"],
+            ExportFormat::Bmp => &["bmp"],
+            ExportFormat::Tga => &["tga"],
+            ExportFormat::Gif => &["gif"],
+            ExportFormat::WebP => &["webp"],
+            ExportFormat::Ico => &["ico"],
+            ExportFormat::Jpeg => &["jpg", "jpeg"],
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<Self> {
+        let ext = std::path::Path::new(path).extension()?.to_str()?;
+        Self::ALL.into_iter().find(|f| {
+            f.extensions()
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(ext))
+        })
+    }
+
+    fn image_format(self) -> image::ImageFormat {
+        match self {
+            ExportFormat::Png => image::ImageFormat::Png,
+            ExportFormat::Bmp => image::ImageFormat::Bmp,
+            ExportFormat::Tga => image::ImageFormat::Tga,
+            ExportFormat::Gif => image::ImageFormat::Gif,
+            ExportFormat::WebP => image::ImageFormat::WebP,
+            ExportFormat::Ico => image::ImageFormat::Ico,
+            ExportFormat::Jpeg => image::ImageFormat::Jpeg,
+        }
+    }
+}
+
+/// JPEG has no transparency, so transparent pixels are blended onto `matte`.
+#[derive(Debug, Clone, Copy)]
+pub struct JpegOptions {
+    /// 1-100
+    pub quality: u8,
+    pub matte: Color,
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        Self {
+            quality: 90,
+            matte: WHITE,
+        }
+    }
+}
+
+pub fn rgba_image(img: &Image) -> RgbaImage {
+    RgbaImage::from_raw(img.width as u32, img.height as u32, img.bytes.clone())
+        .expect("macroquad images are always RGBA8")
+}
+
+/// Converts an image created by `Canvas::to_image_all`/`Layer::to_image`, which are upside down
+/// (see `Layer::to_image`), back into the right way up.
+pub fn rgba_image_from_flipped(img: &Image) -> RgbaImage {
+    let mut out = rgba_image(img);
+    image::imageops::flip_vertical_in_place(&mut out);
+    out
+}
+
+/// Crops away the fully transparent border of `img`. Returns None if every pixel is transparent.
+pub fn trim_transparent(img: &RgbaImage) -> Option<RgbaImage> {
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, p) in img.enumerate_pixels() {
+        if p[3] != 0 {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    if min_x > max_x {
+        return None;
+    }
+    Some(image::imageops::crop_imm(img, min_x, min_y, max_x - min_x + 1, max_y - min_y + 1).to_image())
+}
+
+pub const MAX_EXPORT_SCALE: u32 = 32;
+
+/// Scales `img` up by an integer `factor` using nearest-neighbour, so pixels stay sharp.
+pub fn upscale(img: &RgbaImage, factor: u32) -> RgbaImage {
+    let factor = factor.clamp(1, MAX_EXPORT_SCALE);
+    if factor == 1 {
+        return img.clone();
+    }
+    image::imageops::resize(
+        img,
+        img.width() * factor,
+        img.height() * factor,
+        image::imageops::FilterType::Nearest,
+    )
+}
+
+pub fn export_image(
+    img: &RgbaImage,
+    path: &str,
+    format: ExportFormat,
+    jpeg: &JpegOptions,
+) -> ImageResult<()> {
+    use image::codecs::jpeg::JpegEncoder;
+    use std::io::Write;
+
+    if format != ExportFormat::Jpeg {
+        return img.save_with_format(path, format.image_format());
+    }
+
+    let flat = flatten_onto(img, jpeg.matte);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    JpegEncoder::new_with_quality(&mut writer, jpeg.quality.clamp(1, 100)).encode_image(&flat)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn flatten_onto(img: &RgbaImage, matte: Color) -> image::RgbImage {
+    let matte: [u8; 4] = matte.into();
+    image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let p = img.get_pixel(x, y);
+        let a = p[3] as u32;
+        let blend = |c: u8, m: u8| ((c as u32 * a + m as u32 * (255 - a) + 127) / 255) as u8;
+        image::Rgb([
+            blend(p[0], matte[0]),
+            blend(p[1], matte[1]),
+            blend(p[2], matte[2]),
+        ])
+    })
+}
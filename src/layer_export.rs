@@ -0,0 +1,84 @@
+/// Exporting every layer of a canvas as a separate image.
+use std::path::Path;
+
+use crate::canvas::Canvas;
+use crate::error::ProjectResult;
+use crate::image_io::{self, ExportFormat, JpegOptions};
+
+#[derive(Debug, Clone)]
+pub struct LayerExportOptions {
+    /// File name of every layer. `{project}`, `{index}` and `{layer}` are replaced with the
+    /// project name, the position of the layer and the name of the layer. The extension decides
+    /// the format (PNG if it isn't a known one).
+    pub pattern: String,
+    pub skip_empty: bool,
+    /// Crop every layer to the bounds of its non-transparent pixels.
+    pub trim: bool,
+}
+
+impl Default for LayerExportOptions {
+    fn default() -> Self {
+        Self {
+            pattern: "{project}_{index}_{layer}.png".to_string(),
+            skip_empty: true,
+            trim: false,
+        }
+    }
+}
+
+/// Writes the layers, in `Canvas::layers()` order, into `dir`. Returns the number of files
+/// written.
+pub fn export_layers(
+    dir: &Path,
+    project_name: &str,
+    canvas: &Canvas,
+    opts: &LayerExportOptions,
+    scale: u32,
+    jpeg: &JpegOptions,
+) -> ProjectResult<usize> {
+    let mut written = 0;
+    for (i, (_id, layer)) in canvas.layers().iter().enumerate() {
+        if opts.skip_empty && layer.is_empty() {
+            continue;
+        }
+
+        let mut img = image_io::rgba_image(&layer.to_image_unflipped());
+        if opts.trim {
+            // Trimming an empty layer leaves nothing to write
+            let Some(trimmed) = image_io::trim_transparent(&img) else { continue };
+            img = trimmed;
+        }
+        let img = image_io::upscale(&img, scale);
+
+        let mut file_name = file_name(&opts.pattern, project_name, i, &layer.name);
+        let format = match ExportFormat::from_path(&file_name) {
+            Some(f) => f,
+            None => {
+                file_name.push('.');
+                file_name.push_str(ExportFormat::default().extensions()[0]);
+                ExportFormat::default()
+            }
+        };
+
+        let path = dir.join(file_name);
+        image_io::export_image(&img, &path.to_string_lossy(), format, jpeg)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Fills in the placeholders of `pattern`. Characters that can't be used in file names are
+/// replaced with `_`.
+pub fn file_name(pattern: &str, project_name: &str, index: usize, layer_name: &str) -> String {
+    let project_name = if project_name.is_empty() { "untitled" } else { project_name };
+    pattern
+        .replace("{project}", project_name)
+        .replace("{index}", &index.to_string())
+        .replace("{layer}", layer_name)
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -3,15 +3,32 @@ use macroquad::prelude::*;
 use macroquad::ui::{Skin, root_ui, widgets};
 
 mod undo_redo;
+mod aseprite;
+mod batch;
+mod blend;
 mod canvas;
+mod cli;
+mod error;
+mod fill;
 mod project;
+mod openraster;
+mod palette;
+mod png_embed;
+mod project_file;
+mod recent_files;
+mod recovery;
+mod svg;
+mod tileset;
 mod tool_helper;
 mod icons;
+mod image_io;
+mod layer_export;
 
+use blend::BlendMode;
 use canvas::Canvas;
-use project::Project;
+use project::{CloseReason, Project};
 
-const MAX_PIXELS: usize = 64;
+const MAX_PIXELS: usize = 1024;
 const BG_COLOR: Color = Color::from_rgba(90, 90, 90, 255);
 
 const MOVE_CAMERA_KEY: KeyCode = KeyCode::LeftControl;
@@ -19,7 +36,7 @@ const MOVE_CAMERA_KEY: KeyCode = KeyCode::LeftControl;
 const RECT_DIMS: Vec2 = vec2(0.1, 0.1);
 
 const MAX_SCROLL: Vec2 = vec2(3., 3.);
-const MAX_SCROLL_NEG: Vec2 = vec2(0.1, 0.1);
+const MAX_SCROLL_NEG: Vec2 = vec2(0.005, 0.005);
 const SCROLL_BY: f32 = 0.1;
 const MOUSE_MOVE_BY: f32 = 0.01;
 
@@ -34,15 +51,32 @@ pub enum AppState {
 #[derive(Default, Clone)]
 pub struct NewMenuState {
     file_name: String,
-    num_pixels_string: String,
+    width_string: String,
+    height_string: String,
 }
 
 #[derive(Default)]
 pub struct App {
     state: AppState,
+    /// Error shown on the main menu, e.g. when opening a file failed.
+    error: Option<String>,
+    /// Autosaved sessions that were not closed cleanly, offered for restoring on the main menu.
+    recovered: Vec<recovery::RecoveredSession>,
+    /// Set once the app should exit.
+    quit: bool,
+    /// Paths shown in the "Recent Files" list of the main menu, most recent first.
+    recent_files: Vec<String>,
 }
 
 impl App {
+    pub fn new() -> Self {
+        Self {
+            recovered: recovery::list_sessions(),
+            recent_files: recent_files::load(),
+            ..Default::default()
+        }
+    }
+
     /// Handles only KEYBOARD AND MOUSE inputs and not ui.
     pub fn handle_input(&mut self) {
         match &mut self.state {
@@ -51,6 +85,19 @@ impl App {
         }
     }
 
+    /// Called when the user tries to close the window. Gives the open project a chance to ask
+    /// about unsaved changes first.
+    pub fn request_quit(&mut self) {
+        match &mut self.state {
+            AppState::Drawing(proj) => proj.request_close(CloseReason::Quit),
+            _ => self.quit = true,
+        }
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
     // deceptive name, since it not only draws but updates `state`
     pub fn draw(&mut self) {
         let window_size = vec2(screen_width(), screen_height());
@@ -77,7 +124,15 @@ impl App {
                     new_state = Some(AppState::NewMenu(NewMenuState::default()));
                 }
                 if open_old.ui(&mut root_ui()) {
-                    // TODO: Handle this.
+                    match Project::handle_open() {
+                        Ok(Some(proj)) => new_state = Some(AppState::Drawing(Box::new(proj))),
+                        Ok(None) => {}
+                        Err(e) => self.error = Some(format!("Could not open file: {e}")),
+                    }
+                }
+
+                if let Some(ns) = self.draw_main_menu_ui() {
+                    new_state = Some(ns);
                 }
             }
 
@@ -90,31 +145,44 @@ impl App {
                         .anchor(egui::Align2::CENTER_CENTER, (-25., -50.))
                         .show(egui_ctx, |ui| {
                             let file_name_input = egui::TextEdit::singleline(&mut st.file_name);
-                            let num_pixels_input =
-                                egui::TextEdit::singleline(&mut st.num_pixels_string);
 
                             ui.label("Enter project name:");
                             let _ = ui.add(file_name_input);
 
                             ui.label("Enter project dimensions");
-                            if ui.add(num_pixels_input).changed() {
-                                // HACK
-                                if let Ok(v) = st.num_pixels_string.parse::<usize>() {
-                                    if v > MAX_PIXELS {
-                                        st.num_pixels_string = MAX_PIXELS.to_string();
+                            ui.horizontal(|ui| {
+                                for (i, dim) in [&mut st.width_string, &mut st.height_string]
+                                    .into_iter()
+                                    .enumerate()
+                                {
+                                    if i == 1 {
+                                        ui.label("x");
+                                    }
+                                    let input = egui::TextEdit::singleline(dim).desired_width(40.);
+                                    if ui.add(input).changed() {
+                                        // HACK
+                                        if let Ok(v) = dim.parse::<usize>() {
+                                            if v > MAX_PIXELS {
+                                                *dim = MAX_PIXELS.to_string();
+                                            }
+                                        } else {
+                                            dim.clear();
+                                        }
                                     }
-                                } else {
-                                    st.num_pixels_string.clear();
-                                }
-                            }
-
-                            if ui.button("Done!").clicked() {
-                                if let Ok(v) = st.num_pixels_string.parse::<usize>() {
-                                    new_state = Some(AppState::Drawing(Box::new(Project::new(
-                                        st.file_name.clone(),
-                                        v,
-                                    ))));
                                 }
+                            });
+
+                            if ui.button("Done!").clicked()
+                                && let (Ok(w), Ok(h)) =
+                                    (st.width_string.parse::<usize>(), st.height_string.parse::<usize>())
+                                && w > 0
+                                && h > 0
+                            {
+                                new_state = Some(AppState::Drawing(Box::new(Project::new(
+                                    st.file_name.clone(),
+                                    w,
+                                    h,
+                                ))));
                             }
                         });
                 });
@@ -123,6 +191,13 @@ impl App {
 
             AppState::Drawing(ref mut proj) => {
                 proj.draw();
+                match proj.take_closed() {
+                    Some(CloseReason::NewProject) => {
+                        new_state = Some(AppState::NewMenu(NewMenuState::default()));
+                    }
+                    Some(CloseReason::Quit) => self.quit = true,
+                    None => {}
+                }
             }
         }
         if let Some(ns) = new_state {
@@ -130,6 +205,101 @@ impl App {
         }
     }
 
+    /// Draws the egui windows of the main menu (recent files, recovered sessions and errors).
+    /// Returns the new state if a project was opened.
+    fn draw_main_menu_ui(&mut self) -> Option<AppState> {
+        let mut open = None;
+        let mut restore = None;
+        let mut discard = None;
+        let mut dismiss_error = false;
+
+        egui_macroquad::ui(|egui_ctx| {
+            if !self.recent_files.is_empty() {
+                egui::Window::new("Recent Files")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::LEFT_TOP, (20., 20.))
+                    .show(egui_ctx, |ui| {
+                        for path in self.recent_files.iter() {
+                            let name = std::path::Path::new(path)
+                                .file_name()
+                                .map(|n| n.to_string_lossy())
+                                .unwrap_or(path.into());
+                            if ui.button(name).on_hover_text(path).clicked() {
+                                open = Some(path.clone());
+                            }
+                        }
+                    });
+            }
+
+            if !self.recovered.is_empty() {
+                egui::Window::new("Recover Unsaved Work")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_BOTTOM, (0., -20.))
+                    .show(egui_ctx, |ui| {
+                        for (i, session) in self.recovered.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                let name = if session.project_name.is_empty() {
+                                    "Untitled"
+                                } else {
+                                    &session.project_name
+                                };
+                                ui.label(name);
+                                if ui.button("Restore").clicked() {
+                                    restore = Some(i);
+                                }
+                                if ui.button("Discard").clicked() {
+                                    discard = Some(i);
+                                }
+                            });
+                        }
+                    });
+            }
+
+            if let Some(e) = &self.error {
+                egui::Window::new("Error")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_TOP, (0., 20.))
+                    .show(egui_ctx, |ui| {
+                        ui.label(e);
+                        dismiss_error = ui.button("OK").clicked();
+                    });
+            }
+        });
+        egui_macroquad::draw();
+
+        if dismiss_error {
+            self.error = None;
+        }
+
+        if let Some(path) = open {
+            match Project::load(&path) {
+                Ok(proj) => return Some(AppState::Drawing(Box::new(proj))),
+                Err(e) => {
+                    self.error = Some(format!("Could not open file: {e}"));
+                    recent_files::remove(&path);
+                    self.recent_files.retain(|p| *p != path);
+                }
+            }
+        }
+
+        if let Some(i) = discard {
+            let session = self.recovered.remove(i);
+            recovery::discard(session.id);
+        } else if let Some(i) = restore {
+            match Project::restore(&self.recovered[i]) {
+                Ok(proj) => {
+                    self.recovered.remove(i);
+                    return Some(AppState::Drawing(Box::new(proj)));
+                }
+                Err(e) => self.error = Some(format!("Could not restore project: {e}")),
+            }
+        }
+        None
+    }
+
     pub fn set_state(&mut self, new_state: AppState) {
         self.state = new_state;
     }
@@ -199,24 +369,49 @@ pub struct ToolInfo {
     pixel_indices: Option<Vec<usize>>,
 }
 
-#[derive(Default)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Layer {
-    num_pixels: usize,
+    width: usize,
+    height: usize,
     data: Vec<Option<Color>>,
+    name: String,
+    /// Hidden layers are left out when compositing, but can still be drawn on.
+    visible: bool,
+    /// 0.0 to 1.0, multiplies the alpha of every pixel when compositing.
+    opacity: f32,
+    /// Locked layers can't be changed by the tools.
+    locked: bool,
+    blend_mode: BlendMode,
 }
 
 impl Layer {
-    pub fn new(num_pixels: usize) -> Self {
+    pub fn new(width: usize, height: usize) -> Self {
         let mut d = Vec::<Option<Color>>::new();
-        for _ in 0..(num_pixels * num_pixels) {
+        for _ in 0..(width * height) {
             d.push(None);
         }
         Self {
-            num_pixels,
+            width,
+            height,
             data: d,
+            name: "Layer".to_string(),
+            visible: true,
+            opacity: 1.0,
+            locked: false,
+            blend_mode: BlendMode::Normal,
         }
     }
 
+    /// Same as `new`, but named `name`.
+    pub fn named(width: usize, height: usize, name: impl Into<String>) -> Self {
+        Self { name: name.into(), ..Self::new(width, height) }
+    }
+
+    /// True if no pixel has been drawn on.
+    pub fn is_empty(&self) -> bool {
+        self.data.iter().all(Option::is_none)
+    }
+
     /// Panics: If index >= self.data.len()
     pub fn set_pixel(&mut self, index: usize, c: Option<Color>) {
         self.data[index] = c;
@@ -229,11 +424,11 @@ impl Layer {
 
     /// Flips the image along y-axis. See unflipped version for this function ot achieve otherwise
     pub fn to_image(&self) -> Image {
-        let mut img = Image::gen_image_color(self.num_pixels as u16,self.num_pixels as u16,Color::from_rgba(0,0,0,0)); 
+        let mut img = Image::gen_image_color(self.width as u16,self.height as u16,Color::from_rgba(0,0,0,0)); 
         for (i,p) in self.data.iter().enumerate() {
-            let x = (i % self.num_pixels) as u32;
+            let x = (i % self.width) as u32;
             // We have to do this because otherwise the image is upside down
-            let y = (self.num_pixels - 1 - (i / self.num_pixels)) as u32;
+            let y = (self.height - 1 - (i / self.width)) as u32;
 
             if let Some(c) = p {
                 img.set_pixel(x,y,*c);
@@ -243,11 +438,30 @@ impl Layer {
        img
     }
 
+    /// A nearest-neighbour scaled down copy that fits in `max_size` by `max_size`, for previews.
+    /// Not upside down. Layers that are small enough are copied as they are.
+    pub fn to_thumbnail(&self, max_size: usize) -> Image {
+        let scale = (max_size as f32 / self.width.max(self.height) as f32).min(1.0);
+        let w = ((self.width as f32 * scale) as usize).max(1);
+        let h = ((self.height as f32 * scale) as usize).max(1);
+
+        let mut img = Image::gen_image_color(w as u16, h as u16, Color::from_rgba(0,0,0,0));
+        for y in 0..h {
+            for x in 0..w {
+                let i = x * self.width / w + (y * self.height / h) * self.width;
+                if let Some(c) = self.data[i] {
+                    img.set_pixel(x as u32, y as u32, c);
+                }
+            }
+        }
+        img
+    }
+
     pub fn to_image_unflipped(&self) -> Image {
-        let mut img = Image::gen_image_color(self.num_pixels as u16,self.num_pixels as u16,Color::from_rgba(0,0,0,0)); 
+        let mut img = Image::gen_image_color(self.width as u16,self.height as u16,Color::from_rgba(0,0,0,0)); 
         for (i,p) in self.data.iter().enumerate() {
-            let x = (i % self.num_pixels) as u32;
-            let y = (i / self.num_pixels) as u32;
+            let x = (i % self.width) as u32;
+            let y = (i / self.width) as u32;
 
             if let Some(c) = p {
                 img.set_pixel(x,y,*c);
@@ -259,9 +473,17 @@ impl Layer {
 
 }
 
-#[macroquad::main("Pixeditor")]
-async fn main() {
-    let mut app = App::default();
+fn main() {
+    // Any arguments mean a command line run, which must not open a window
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+    macroquad::Window::new("Pixeditor", run_app());
+}
+
+async fn run_app() {
+    let mut app = App::new();
 
     let window_style = root_ui()
         .style_builder()
@@ -290,10 +512,19 @@ async fn main() {
 
     root_ui().push_skin(&skin);
 
+    // Closing the window is handled by App so that unsaved changes aren't lost
+    prevent_quit();
+
     loop {
+        if is_quit_requested() {
+            app.request_quit();
+        }
         app.handle_input();
         clear_background(BG_COLOR);
         app.draw();
+        if app.should_quit() {
+            break;
+        }
         next_frame().await;
     }
 }
@@ -0,0 +1,209 @@
+/// Reading and writing of OpenRaster (`.ora`) files, the layered format understood by Krita, GIMP
+/// and MyPaint.
+///
+/// An OpenRaster file is a zip archive containing a `mimetype` entry, one PNG per layer,
+/// `stack.xml` describing the layer stack (topmost layer first), the flattened `mergedimage.png`
+/// and a thumbnail.
+///
+/// See https://www.openraster.org/ for the format.
+use std::io::{Read, Seek, Write};
+
+use crate::blend::BlendMode;
+use crate::canvas::{Canvas, LayerId};
+use crate::error::{ProjectResult, invalid_data};
+use crate::image_io::{self, ImportedCanvas};
+use crate::{Layer, MAX_PIXELS};
+
+use image::RgbaImage;
+use image::codecs::png::PngEncoder;
+use indexmap::IndexMap;
+use macroquad::prelude::RED;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+pub const OPENRASTER_EXTENSION: &str = "ora";
+
+const MIMETYPE: &str = "image/openraster";
+const THUMBNAIL_SIZE: u32 = 256;
+
+pub fn is_openraster_path(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(OPENRASTER_EXTENSION))
+}
+
+pub fn write<W: Write + Seek>(w: W, canvas: &Canvas) -> ProjectResult<()> {
+    let (width, height) = (canvas.width(), canvas.height());
+    let mut zip = ZipWriter::new(w);
+    // PNGs are already compressed
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // Must be the first entry, and uncompressed
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(MIMETYPE.as_bytes())?;
+
+    let mut stack = format!(
+        "<?xml version='1.0' encoding='UTF-8'?>\n<image version=\"0.0.3\" w=\"{width}\" h=\"{height}\">\n  <stack>\n"
+    );
+    // stack.xml lists the topmost layer first
+    for (i, (_id, layer)) in canvas.layers().iter().enumerate().rev() {
+        let src = format!("data/layer{i}.png");
+        zip.start_file(src.as_str(), stored)?;
+        zip.write_all(&encode_png(&image_io::rgba_image(&layer.to_image_unflipped()))?)?;
+
+        stack.push_str(&format!(
+            "    <layer name=\"{}\" src=\"{src}\" x=\"0\" y=\"0\" opacity=\"{:.3}\" visibility=\"{}\" composite-op=\"{}\"/>\n",
+            escape_xml(&layer.name),
+            layer.opacity,
+            if layer.visible { "visible" } else { "hidden" },
+            composite_op(layer.blend_mode),
+        ));
+    }
+    stack.push_str("  </stack>\n</image>\n");
+
+    zip.start_file("stack.xml", deflated)?;
+    zip.write_all(stack.as_bytes())?;
+
+    let merged = image_io::rgba_image_from_flipped(&canvas.to_image_all());
+    zip.start_file("mergedimage.png", stored)?;
+    zip.write_all(&encode_png(&merged)?)?;
+
+    // Thumbnails may be at most 256x256, which pixel art rarely exceeds anyway
+    let thumbnail = if width.max(height) as u32 > THUMBNAIL_SIZE {
+        // Keep the aspect ratio
+        let scale = THUMBNAIL_SIZE as f32 / width.max(height) as f32;
+        image::imageops::resize(
+            &merged,
+            ((width as f32 * scale) as u32).max(1),
+            ((height as f32 * scale) as u32).max(1),
+            image::imageops::FilterType::Nearest,
+        )
+    } else {
+        merged
+    };
+    zip.start_file("Thumbnails/thumbnail.png", stored)?;
+    zip.write_all(&encode_png(&thumbnail)?)?;
+
+    zip.finish()?;
+    Ok(())
+}
+
+pub fn read<R: Read + Seek>(r: R) -> ProjectResult<ImportedCanvas> {
+    let mut zip = ZipArchive::new(r)?;
+
+    let mut stack_xml = String::new();
+    zip.by_name("stack.xml")?.read_to_string(&mut stack_xml)?;
+    let doc = roxmltree::Document::parse(&stack_xml).map_err(invalid_data)?;
+
+    let image_node = doc.root_element();
+    let width = attr_num::<usize>(image_node, "w")
+        .ok_or_else(|| invalid_data("missing image width"))?;
+    let height = attr_num::<usize>(image_node, "h")
+        .ok_or_else(|| invalid_data("missing image height"))?;
+    if !(1..=MAX_PIXELS).contains(&width) || !(1..=MAX_PIXELS).contains(&height) {
+        return Err(invalid_data(format!(
+            "image must be between 1 and {MAX_PIXELS} pixels wide and high"
+        ))
+        .into());
+    }
+
+    let root_stack = image_node
+        .children()
+        .find(|n| n.has_tag_name("stack"))
+        .ok_or_else(|| invalid_data("missing layer stack"))?;
+
+    let mut warnings = Vec::new();
+    let mut nodes = Vec::new();
+    collect_layers(root_stack, &mut nodes, &mut warnings);
+
+    // Our layers go from bottom to top
+    let mut layers = IndexMap::<LayerId, Layer>::new();
+    for node in nodes.into_iter().rev() {
+        let Some(src) = node.attribute("src") else { continue };
+
+        let mut png = Vec::new();
+        zip.by_name(src)?.read_to_end(&mut png)?;
+        let img = image::load_from_memory(&png)?.into_rgba8();
+
+        let offset = (
+            attr_num::<i64>(node, "x").unwrap_or(0),
+            attr_num::<i64>(node, "y").unwrap_or(0),
+        );
+        let mut layer = image_io::layer_from_image_at(&img, width, height, offset);
+        layer.name = node
+            .attribute("name")
+            .map_or_else(|| format!("Layer {}", layers.len()), str::to_string);
+        layer.visible = node.attribute("visibility") != Some("hidden");
+        layer.opacity = attr_num::<f32>(node, "opacity").unwrap_or(1.0).clamp(0.0, 1.0);
+        let op = node.attribute("composite-op").unwrap_or("svg:src-over");
+        layer.blend_mode = BlendMode::ALL
+            .into_iter()
+            .find(|m| composite_op(*m) == op)
+            .unwrap_or_else(|| {
+                warnings.push("Unsupported layer blend modes were replaced with Normal.".to_string());
+                BlendMode::Normal
+            });
+        layers.insert(LayerId::new_v4(), layer);
+    }
+    if layers.is_empty() {
+        layers.insert(LayerId::new_v4(), Layer::named(width, height, "Layer 0"));
+    }
+    warnings.sort_unstable();
+    warnings.dedup();
+
+    let active = *layers.last().unwrap().0;
+    Ok(ImportedCanvas {
+        canvas: Canvas::from_layers(width, height, layers, active, RED),
+        warnings,
+    })
+}
+
+/// Collects the `<layer>` elements of a stack, topmost first. Nested stacks (groups) are
+/// flattened into their parent.
+fn collect_layers<'a, 'input>(
+    stack: roxmltree::Node<'a, 'input>,
+    out: &mut Vec<roxmltree::Node<'a, 'input>>,
+    warnings: &mut Vec<String>,
+) {
+    for child in stack.children().filter(|n| n.is_element()) {
+        match child.tag_name().name() {
+            "layer" => out.push(child),
+            "stack" => {
+                warnings.push("Layer groups were flattened.".to_string());
+                collect_layers(child, out, warnings);
+            }
+            other => warnings.push(format!("Ignored unsupported <{other}> element.")),
+        }
+    }
+}
+
+/// The OpenRaster name of a blend mode.
+fn composite_op(mode: BlendMode) -> &'static str {
+    match mode {
+        BlendMode::Normal => "svg:src-over",
+        BlendMode::Multiply => "svg:multiply",
+        BlendMode::Screen => "svg:screen",
+        BlendMode::Overlay => "svg:overlay",
+        BlendMode::Add => "svg:plus",
+    }
+}
+
+fn attr_num<T: std::str::FromStr>(node: roxmltree::Node, name: &str) -> Option<T> {
+    node.attribute(name)?.trim().parse().ok()
+}
+
+fn encode_png(img: &RgbaImage) -> image::ImageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    img.write_with_encoder(PngEncoder::new(&mut out))?;
+    Ok(out)
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
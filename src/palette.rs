@@ -0,0 +1,394 @@
+/// Reading and writing of palette files, so that shared palettes can be used as the project
+/// palette.
+///
+/// Supported are GIMP palettes (`.gpl`), Lospec style hex lists (`.hex`), JASC palettes (`.pal`)
+/// and Adobe Swatch Exchange files (`.ase`). None of them store alpha, so palette colors are
+/// always written as opaque.
+use std::io::{self, BufRead, Read, Write};
+
+use crate::error::invalid_data;
+
+use macroquad::prelude::Color;
+
+/// The most colors a palette can have. Plenty for pixel art, and keeps a broken file from
+/// allocating forever.
+pub const MAX_PALETTE_COLORS: usize = 1024;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteFormat {
+    #[default]
+    Gpl,
+    Hex,
+    JascPal,
+    Ase,
+}
+
+impl PaletteFormat {
+    pub const ALL: [PaletteFormat; 4] = [
+        PaletteFormat::Gpl,
+        PaletteFormat::Hex,
+        PaletteFormat::JascPal,
+        PaletteFormat::Ase,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "GIMP Palette",
+            PaletteFormat::Hex => "Hex Palette",
+            PaletteFormat::JascPal => "JASC Palette",
+            PaletteFormat::Ase => "Adobe Swatch Exchange",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            PaletteFormat::Gpl => "gpl",
+            PaletteFormat::Hex => "hex",
+            PaletteFormat::JascPal => "pal",
+            PaletteFormat::Ase => "ase",
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<Self> {
+        let ext = std::path::Path::new(path).extension()?.to_str()?;
+        Self::ALL
+            .into_iter()
+            .find(|f| f.extension().eq_ignore_ascii_case(ext))
+    }
+}
+
+pub fn read<R: BufRead>(r: &mut R, format: PaletteFormat) -> io::Result<Vec<Color>> {
+    let colors = match format {
+        PaletteFormat::Gpl => read_gpl(r)?,
+        PaletteFormat::Hex => read_hex(r)?,
+        PaletteFormat::JascPal => read_jasc_pal(r)?,
+        PaletteFormat::Ase => read_ase(r)?,
+    };
+    if colors.len() > MAX_PALETTE_COLORS {
+        return Err(invalid_data(format!(
+            "palettes can have at most {MAX_PALETTE_COLORS} colors"
+        )));
+    }
+    Ok(colors)
+}
+
+/// Writes `colors` as a palette. `name` is stored by the formats that have a palette name.
+pub fn write<W: Write>(
+    w: &mut W,
+    format: PaletteFormat,
+    name: &str,
+    colors: &[Color],
+) -> io::Result<()> {
+    match format {
+        PaletteFormat::Gpl => write_gpl(w, name, colors),
+        PaletteFormat::Hex => write_hex(w, colors),
+        PaletteFormat::JascPal => write_jasc_pal(w, colors),
+        PaletteFormat::Ase => write_ase(w, colors),
+    }
+}
+
+/// The palette color closest to `color` in RGB, with the alpha of `color`. None if the palette is
+/// empty.
+pub fn nearest(palette: &[Color], color: Color) -> Option<Color> {
+    let [r, g, b, a]: [u8; 4] = color.into();
+    palette
+        .iter()
+        .map(|c| {
+            let [pr, pg, pb, _]: [u8; 4] = (*c).into();
+            let dist = [(r, pr), (g, pg), (b, pb)]
+                .iter()
+                .map(|&(x, y)| (x as i32 - y as i32).pow(2))
+                .sum::<i32>();
+            (dist, Color::from_rgba(pr, pg, pb, a))
+        })
+        .min_by_key(|(dist, _)| *dist)
+        .map(|(_, c)| c)
+}
+
+/// ```text
+/// GIMP Palette
+/// Name: Example
+/// Columns: 8
+/// #
+/// 255   0   0 Red
+/// ```
+fn read_gpl<R: BufRead>(r: &mut R) -> io::Result<Vec<Color>> {
+    let mut lines = r.lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if header.trim() != "GIMP Palette" {
+        return Err(invalid_data("not a GIMP palette"));
+    }
+
+    let mut colors = Vec::new();
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+
+        // Everything after the three components is the color name
+        let rgb = line
+            .split_whitespace()
+            .take(3)
+            .map(|v| v.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+        let [r, g, b] = rgb[..] else {
+            return Err(invalid_data(format!("invalid palette entry `{line}`")));
+        };
+        colors.push(Color::from_rgba(r, g, b, 255));
+    }
+    Ok(colors)
+}
+
+fn write_gpl<W: Write>(w: &mut W, name: &str, colors: &[Color]) -> io::Result<()> {
+    writeln!(w, "GIMP Palette")?;
+    // Line breaks would end the header early
+    writeln!(w, "Name: {}", name.replace(['\r', '\n'], " "))?;
+    writeln!(w, "Columns: {}", colors.len().clamp(1, 16))?;
+    writeln!(w, "#")?;
+    for c in colors {
+        let [r, g, b, _]: [u8; 4] = (*c).into();
+        writeln!(w, "{r:3} {g:3} {b:3}\t#{r:02x}{g:02x}{b:02x}")?;
+    }
+    Ok(())
+}
+
+/// One `rrggbb` color per line, as downloaded from Lospec. `rrggbbaa` and a leading `#` are
+/// accepted too.
+fn read_hex<R: BufRead>(r: &mut R) -> io::Result<Vec<Color>> {
+    let mut colors = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let hex = line.strip_prefix('#').unwrap_or(line);
+        colors.push(parse_hex(hex).ok_or_else(|| invalid_data(format!("invalid color `{line}`")))?);
+    }
+    Ok(colors)
+}
+
+fn write_hex<W: Write>(w: &mut W, colors: &[Color]) -> io::Result<()> {
+    for c in colors {
+        let [r, g, b, _]: [u8; 4] = (*c).into();
+        writeln!(w, "{r:02x}{g:02x}{b:02x}")?;
+    }
+    Ok(())
+}
+
+fn parse_hex(hex: &str) -> Option<Color> {
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    let alpha = if hex.len() == 8 { component(6)? } else { 255 };
+    Some(Color::from_rgba(component(0)?, component(2)?, component(4)?, alpha))
+}
+
+/// ```text
+/// JASC-PAL
+/// 0100
+/// 2
+/// 255 0 0
+/// 0 0 255
+/// ```
+fn read_jasc_pal<R: BufRead>(r: &mut R) -> io::Result<Vec<Color>> {
+    let mut lines = r.lines();
+    let mut next_line = || -> io::Result<String> {
+        lines
+            .next()
+            .transpose()?
+            .ok_or_else(|| invalid_data("unexpected end of palette"))
+    };
+
+    let header = next_line()?;
+    if header.trim() != "JASC-PAL" {
+        // Windows' RIFF palettes share the extension but are binary
+        return Err(invalid_data("not a JASC palette"));
+    }
+    let _version = next_line()?;
+    let count = next_line()?
+        .trim()
+        .parse::<usize>()
+        .map_err(invalid_data)?;
+    if count > MAX_PALETTE_COLORS {
+        return Err(invalid_data(format!(
+            "palettes can have at most {MAX_PALETTE_COLORS} colors"
+        )));
+    }
+
+    let mut colors = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = next_line()?;
+        let rgb = line
+            .split_whitespace()
+            .map(|v| v.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+        let [r, g, b] = rgb[..] else {
+            return Err(invalid_data(format!("invalid palette entry `{}`", line.trim())));
+        };
+        colors.push(Color::from_rgba(r, g, b, 255));
+    }
+    Ok(colors)
+}
+
+fn write_jasc_pal<W: Write>(w: &mut W, colors: &[Color]) -> io::Result<()> {
+    // The format uses Windows line endings
+    write!(w, "JASC-PAL\r\n0100\r\n{}\r\n", colors.len())?;
+    for c in colors {
+        let [r, g, b, _]: [u8; 4] = (*c).into();
+        write!(w, "{r} {g} {b}\r\n")?;
+    }
+    Ok(())
+}
+
+const ASE_MAGIC: &[u8; 4] = b"ASEF";
+const ASE_BLOCK_COLOR: u16 = 0x0001;
+/// Global colors, as opposed to spot or process colors. We don't care about the difference.
+const ASE_COLOR_GLOBAL: u16 = 0;
+
+/// Adobe Swatch Exchange, all big-endian: magic `ASEF`, version 1.0 as two `u16`s, a `u32` block
+/// count, then blocks of a `u16` type and a `u32` length. Color blocks hold a UTF-16 name, a four
+/// character color model and its `f32` components. Group blocks are skipped, so groups are
+/// flattened.
+fn read_ase<R: Read>(r: &mut R) -> io::Result<Vec<Color>> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != ASE_MAGIC {
+        return Err(invalid_data("not an Adobe Swatch Exchange file"));
+    }
+    let _version = (read_u16(r)?, read_u16(r)?);
+    let num_blocks = read_u32(r)?;
+
+    let mut colors = Vec::new();
+    for _ in 0..num_blocks {
+        let kind = read_u16(r)?;
+        let len = read_u32(r)? as u64;
+        let mut block = Vec::new();
+        r.by_ref().take(len).read_to_end(&mut block)?;
+        if block.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if kind != ASE_BLOCK_COLOR {
+            continue;
+        }
+
+        let mut block = &block[..];
+        let name_len = read_u16(&mut block)? as usize;
+        block = block.get(name_len * 2..).ok_or_else(|| invalid_data("invalid swatch name"))?;
+        let mut model = [0u8; 4];
+        block.read_exact(&mut model)?;
+
+        let mut components = [0f32; 4];
+        let num_components = match &model {
+            b"RGB " => 3,
+            b"CMYK" => 4,
+            b"Gray" => 1,
+            // Lab would need a white point to convert, which the file doesn't have
+            b"LAB " => continue,
+            _ => return Err(invalid_data("unknown swatch color model")),
+        };
+        for c in components.iter_mut().take(num_components) {
+            *c = read_f32(&mut block)?.clamp(0.0, 1.0);
+        }
+
+        colors.push(match &model {
+            b"RGB " => Color::new(components[0], components[1], components[2], 1.0),
+            b"CMYK" => {
+                let [c, m, y, k] = components;
+                Color::new((1. - c) * (1. - k), (1. - m) * (1. - k), (1. - y) * (1. - k), 1.0)
+            }
+            _ => Color::new(components[0], components[0], components[0], 1.0),
+        });
+    }
+    Ok(colors)
+}
+
+fn write_ase<W: Write>(w: &mut W, colors: &[Color]) -> io::Result<()> {
+    w.write_all(ASE_MAGIC)?;
+    w.write_all(&1u16.to_be_bytes())?;
+    w.write_all(&0u16.to_be_bytes())?;
+    w.write_all(&(colors.len() as u32).to_be_bytes())?;
+
+    for c in colors {
+        let [r, g, b, _]: [u8; 4] = (*c).into();
+        // Swatches are named after their hex code, null terminated
+        let name = format!("#{r:02x}{g:02x}{b:02x}\0").encode_utf16().collect::<Vec<_>>();
+
+        let mut block = Vec::new();
+        block.extend((name.len() as u16).to_be_bytes());
+        block.extend(name.iter().flat_map(|c| c.to_be_bytes()));
+        block.extend(b"RGB ");
+        for v in [r, g, b] {
+            block.extend((v as f32 / 255.).to_be_bytes());
+        }
+        block.extend(ASE_COLOR_GLOBAL.to_be_bytes());
+
+        w.write_all(&ASE_BLOCK_COLOR.to_be_bytes())?;
+        w.write_all(&(block.len() as u32).to_be_bytes())?;
+        w.write_all(&block)?;
+    }
+    Ok(())
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(r)?))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let colors = [[0, 0, 0, 255], [255, 128, 1, 255], [18, 52, 86, 255]]
+            .map(|[r, g, b, a]| Color::from_rgba(r, g, b, a));
+        for format in PaletteFormat::ALL {
+            let mut bytes = Vec::new();
+            write(&mut bytes, format, "Test", &colors).unwrap();
+            let read = read(&mut &bytes[..], format).unwrap();
+
+            let rgba = |c: &[Color]| c.iter().map(|&c| c.into()).collect::<Vec<[u8; 4]>>();
+            assert_eq!(rgba(&read), rgba(&colors), "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn alpha_is_not_stored() {
+        let colors = [Color::from_rgba(10, 20, 30, 40)];
+        for format in PaletteFormat::ALL {
+            let mut bytes = Vec::new();
+            write(&mut bytes, format, "", &colors).unwrap();
+            let read: [u8; 4] = read(&mut &bytes[..], format).unwrap()[0].into();
+            assert_eq!(read, [10, 20, 30, 255], "{}", format.name());
+        }
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(PaletteFormat::from_path("a/b.GPL"), Some(PaletteFormat::Gpl));
+        assert_eq!(PaletteFormat::from_path("colors.pal"), Some(PaletteFormat::JascPal));
+        assert_eq!(PaletteFormat::from_path("colors.png"), None);
+    }
+}
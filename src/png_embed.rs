@@ -0,0 +1,85 @@
+/// Embedding the whole layered project inside an exported PNG.
+///
+/// The project is stored (in the native project format, zlib compressed) in a private ancillary
+/// `pxED` chunk, which other programs ignore. So the file still opens as a flat image anywhere
+/// else, but pixeditor reopens it with all layers intact.
+use std::io::{self, Read, Write};
+
+use crate::canvas::Canvas;
+use crate::error::invalid_data;
+use crate::project_file;
+
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use image::RgbaImage;
+use image::codecs::png::PngEncoder;
+
+/// Ancillary (`p`), private (`x`), not safe to copy (`D`): an edited image must not keep layers
+/// that no longer match it.
+const CHUNK_TYPE: &[u8; 4] = b"pxED";
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+const IEND_CHUNK_SIZE: usize = 12;
+/// Most bytes the embedded project may decompress to, so a tiny chunk can't fill the memory.
+const MAX_DECODED_LEN: u64 = 256 * 1024 * 1024;
+
+/// Writes `img` as a PNG with the project embedded in it.
+pub fn write_png(
+    img: &RgbaImage,
+    path: &str,
+    project_name: &str,
+    canvas: &Canvas,
+) -> image::ImageResult<()> {
+    let mut png = Vec::new();
+    img.write_with_encoder(PngEncoder::new(&mut png))?;
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    project_file::write_project(&mut encoder, project_name, canvas)?;
+    let data = encoder.finish()?;
+
+    // IEND is always last, put our chunk right before it
+    let iend = png.len() - IEND_CHUNK_SIZE;
+    let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(&png[..iend])?;
+    write_chunk(&mut file, CHUNK_TYPE, &data)?;
+    file.write_all(&png[iend..])?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Returns the embedded project name and canvas, or None if the PNG has no embedded project.
+pub fn read_embedded(path: &str) -> io::Result<Option<(String, Canvas)>> {
+    let png = std::fs::read(path)?;
+    if !png.starts_with(PNG_SIGNATURE) {
+        return Ok(None);
+    }
+
+    let mut rest = &png[PNG_SIGNATURE.len()..];
+    while rest.len() >= 12 {
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let kind = &rest[4..8];
+        let Some(data) = rest.get(8..8 + len) else { break };
+
+        if kind == CHUNK_TYPE {
+            let mut decoded = Vec::new();
+            ZlibDecoder::new(data).take(MAX_DECODED_LEN + 1).read_to_end(&mut decoded)?;
+            if decoded.len() as u64 > MAX_DECODED_LEN {
+                return Err(invalid_data("embedded project is too large"));
+            }
+            return project_file::read_project(&mut &decoded[..]).map(Some);
+        }
+        rest = &rest[(12 + len).min(rest.len())..];
+    }
+    Ok(None)
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(data);
+
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc.sum().to_be_bytes())
+}
@@ -1,6 +1,21 @@
-use crate::{Canvas, ToolKind, FillType, MoveCameraData, Tool, undo_redo::{ ActionsManager, Action }};
+use crate::aseprite::{self, ASEPRITE_EXTENSIONS};
+use crate::blend::BlendMode;
+use crate::canvas::LayerId;
+use crate::error::{self, ProjectError, ProjectResult};
+use crate::fill::Connectivity;
+use crate::image_io::{self, ImportedCanvas, ExportFormat, ImportFit, ImportOptions, ImportPlacement, JpegOptions, MAX_EXPORT_SCALE, OPEN_IMAGE_EXTENSIONS};
+use crate::layer_export::{self, LayerExportOptions};
+use crate::openraster::{self, OPENRASTER_EXTENSION};
+use crate::palette::{self, PaletteFormat};
+use crate::png_embed;
+use crate::project_file::{self, PROJECT_EXTENSION};
+use crate::recent_files;
+use crate::recovery::{self, AUTOSAVE_INTERVAL, RecoveredSession, SessionId};
+use crate::svg::{self, SvgOptions, SVG_EXTENSION};
+use crate::tileset::{self, TilesetOptions};
+use crate::{Canvas, ToolKind, FillType, MoveCameraData, Tool, undo_redo::{ ActionsManager, Action, LayerInfo }};
 use crate::{
-    SCROLL_BY, MAX_SCROLL, MAX_SCROLL_NEG, MOUSE_MOVE_BY, MOVE_CAMERA_KEY,
+    SCROLL_BY, MAX_SCROLL, MAX_SCROLL_NEG, MOUSE_MOVE_BY, MOVE_CAMERA_KEY, RECT_DIMS,
 };
 
 use egui_macroquad::egui;
@@ -11,7 +26,6 @@ use macroquad::ui::root_ui;
 #[derive(Default)]
 pub struct Project {
     project_name: String,
-    num_pixels: usize,
     /// If the user input (like mouse-click) was processed by the ui.
     ui_processed: bool,
 
@@ -23,21 +37,181 @@ pub struct Project {
 
     action_manager:ActionsManager,
 
+    /// Shown to the user until dismissed.
+    error: Option<ProjectError>,
+    /// Non-fatal information, such as what was skipped when importing a file. Shown until
+    /// dismissed.
+    notice: Option<String>,
+
+    /// Identifies this project's recovery file. See `recovery`.
+    session_id: SessionId,
+    /// If there were changes since the last autosave (or explicit save).
+    needs_autosave: bool,
+    /// `get_time()` of the last autosave.
+    last_autosave: f64,
+    /// If the last autosave failed. The error is only shown for the first failure, not for every
+    /// retry.
+    autosave_failed: bool,
+
+    /// Unsaved changes that aren't tracked by `action_manager` (such as renaming layers).
+    untracked_changes: bool,
+    /// Set while asking the user what to do with unsaved changes before closing.
+    close_prompt: Option<CloseReason>,
+    /// Set once the project may be closed, see `take_closed`.
+    closed: Option<CloseReason>,
+
+    /// If the "Export Layers" window is open.
+    show_layer_export: bool,
+    /// If the "Export Tileset" window is open.
+    show_tileset_export: bool,
+    /// File name and contents of an image chosen with "Import as Layer", while the user picks the
+    /// import options.
+    pending_import: Option<(String, image::RgbaImage)>,
+    import_options: ImportOptions,
+
+    /// Layer previews for the "Layers" window and the canvas revision they were made at.
+    layer_thumbnails: Option<(u64, Vec<egui::TextureHandle>)>,
+}
+
+/// Largest side of a layer preview in the "Layers" window, in screen pixels.
+const THUMBNAIL_SIZE: usize = 48;
+
+/// The default camera, zoomed out if needed so that all of `canvas` is visible.
+fn fitted_camera(canvas: &Canvas) -> Camera2D {
+    let size = vec2(canvas.width() as f32 * RECT_DIMS.x, canvas.height() as f32 * RECT_DIMS.y);
+    // The default view is 2 units across
+    if size.max_element() <= 2.0 {
+        return Camera2D::default();
+    }
+    let zoom = (2.0 / size.max_element()).clamp(MAX_SCROLL_NEG.x, MAX_SCROLL.x);
+    Camera2D {
+        zoom: vec2(zoom, zoom),
+        target: vec2(-1.0, -1.0) + size / 2.0,
+        ..Default::default()
+    }
+}
+
+/// Something the user asked for in the "Layers" window. Applied after the window is drawn, as
+/// the layers can't change while they are being listed.
+enum LayerCommand {
+    Add,
+    Delete(LayerId),
+    /// Move the layer to this index.
+    MoveTo(LayerId, usize),
+    Duplicate(LayerId),
+    MergeDown(LayerId),
+    Flatten,
+}
+
+/// Why the project is being closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    NewProject,
+    Quit,
 }
 
 impl Project {
-    pub fn new(project_name: String, num_pixels: usize) -> Self {
-       Self {
-            project_name:project_name.clone(),
-            num_pixels,
-            canvas: Canvas::new(num_pixels),
-            save_options:SaveOptions::with_name(project_name),
+    pub fn new(project_name: String, width: usize, height: usize) -> Self {
+        Self::from_canvas(project_name, Canvas::new(width, height))
+    }
+
+    pub fn from_canvas(project_name: String, canvas: Canvas) -> Self {
+        Self {
+            project_name: project_name.clone(),
+            camera: fitted_camera(&canvas),
+            canvas,
+            save_options: SaveOptions::with_name(project_name),
+            session_id: SessionId::new_v4(),
             ..Default::default()
         }
     }
 
+    /// Opens anything `load` can read. The file is added to the recent files list, and is also
+    /// remembered as the save location. Anything that couldn't be imported is shown as a notice,
+    /// and then the file isn't the save location: saving would overwrite it with less than it had.
+    pub fn load(path: &str) -> ProjectResult<Self> {
+        let (project_name, import) = load(path)?;
+        let mut proj = Self::from_canvas(project_name, import.canvas);
+        if import.warnings.is_empty() && is_save_path(path) {
+            proj.set_save_path(path);
+        }
+        // PNGs only have several layers if a project was embedded in them, keep those when saving
+        // back to the same file
+        let is_png = ExportFormat::from_path(path) == Some(ExportFormat::Png);
+        if is_png && proj.canvas.layers().len() > 1 {
+            proj.save_options.embed_layers = true;
+        }
+        if !import.warnings.is_empty() {
+            proj.notice = Some(import.warnings.join("\n"));
+        }
+        recent_files::add(path);
+        Ok(proj)
+    }
+
+    /// Reopens an autosaved session. The project keeps using the same recovery file, and has no
+    /// save location since it may never have been saved.
+    pub fn restore(session: &RecoveredSession) -> ProjectResult<Self> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(&session.path)?);
+        let (project_name, canvas) = project_file::read_project(&mut reader)?;
+
+        let mut proj = Self::from_canvas(project_name, canvas);
+        proj.session_id = session.id;
+        proj.untracked_changes = true;
+        Ok(proj)
+    }
+
+    fn set_save_path(&mut self, path: &str) {
+        if let Some(file_name) = std::path::Path::new(path).file_name() {
+            self.save_options.set_name(file_name.to_string_lossy().into_owned());
+        }
+        self.save_options.set_path(path.to_string());
+    }
+
+    /// Asks the user for a project or image to open. Returns None if the dialog was closed.
+    pub fn handle_open() -> ProjectResult<Option<Self>> {
+        use native_dialog::DialogBuilder;
+
+        let path = DialogBuilder::file()
+            .set_location("~/Desktop")
+            .add_filter("Pixeditor Project", [PROJECT_EXTENSION])
+            .add_filter("Image", OPEN_IMAGE_EXTENSIONS)
+            .add_filter("Aseprite", ASEPRITE_EXTENSIONS)
+            .add_filter("OpenRaster", [OPENRASTER_EXTENSION])
+            .open_single_file()
+            .show()?;
+
+        match path {
+            Some(path) => Ok(Some(Self::load(&error::path_to_string(path)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.untracked_changes || self.action_manager.is_dirty()
+    }
+
+    /// Closes the project, first asking the user to save it if there are unsaved changes.
+    pub fn request_close(&mut self, reason: CloseReason) {
+        if self.is_dirty() {
+            self.close_prompt = Some(reason);
+        } else {
+            self.close(reason);
+        }
+    }
+
+    fn close(&mut self, reason: CloseReason) {
+        recovery::discard(self.session_id);
+        self.close_prompt = None;
+        self.closed = Some(reason);
+    }
+
+    /// Returns the reason once the project has been closed, after which it should be dropped.
+    pub fn take_closed(&mut self) -> Option<CloseReason> {
+        self.closed.take()
+    }
+
     pub fn handle_input(&mut self) {
-        if self.ui_processed {
+        if self.ui_processed || self.close_prompt.is_some() {
             return;
         }
 
@@ -69,9 +243,12 @@ impl Project {
     }
 
     pub fn use_tool(&mut self) {
-        if let Some(drawn_pixels) = self.canvas.use_tool_at_mouse_position(&self.camera) {
+        if let Some(drawn_pixels) = self.canvas.use_tool_at_mouse_position(&self.camera)
+            && !drawn_pixels.is_noop()
+        {
             let action = Action::DrawPixels(drawn_pixels);
             self.action_manager.add_action(action);
+            self.needs_autosave = true;
         }
         
     }
@@ -90,17 +267,9 @@ impl Project {
             return;
         }
 
-        if sy > 0.0 {
-            if !(self.camera.zoom.x < MAX_SCROLL.x && self.camera.zoom.y < MAX_SCROLL.y) {
-                self.camera.zoom = MAX_SCROLL;
-                return;
-            }
-        } else if !(self.camera.zoom.x > MAX_SCROLL_NEG.x && self.camera.zoom.y > MAX_SCROLL_NEG.y)
-        {
-            self.camera.zoom = MAX_SCROLL_NEG;
-            return;
-        }
-        self.camera.zoom += sy * SCROLL_BY;
+        // Zooming by a factor keeps the steps even, from huge canvases up to single pixels
+        let factor = (1.0 + SCROLL_BY).powf(sy.signum());
+        self.camera.zoom = (self.camera.zoom * factor).clamp(MAX_SCROLL_NEG, MAX_SCROLL);
     }
 
     pub fn draw_ui(&mut self) {
@@ -114,17 +283,226 @@ impl Project {
             egui::TopBottomPanel::top("top bar").show(egui_ctx, |ui| {
                 egui::menu::bar(ui, |ui| {
                     ui.menu_button("File", |ui| {
+                        if ui.button("New Project").clicked() {
+                            self.request_close(CloseReason::NewProject);
+                        }
+                        ui.separator();
                         if ui.button("Save").clicked() {
-                            self.handle_save();
+                            let res = self.handle_save();
+                            self.report_error(res);
                         }
                         if ui.button("Save As").clicked() {
-                            self.handle_save_as();
+                            let res = self.handle_save_as();
+                            self.report_error(res);
+                        }
+                        if ui.button("Import as Layer...").clicked() {
+                            let res = self.handle_import_layer();
+                            self.report_error(res);
+                            ui.close_menu();
+                        }
+                        if ui.button("Export Layers...").clicked() {
+                            self.show_layer_export = true;
+                            ui.close_menu();
                         }
+                        if ui.button("Export Tileset...").clicked() {
+                            self.show_tileset_export = true;
+                            ui.close_menu();
+                        }
+                        ui.menu_button("Export Options", |ui| {
+                            ui.add(
+                                egui::Slider::new(&mut self.save_options.scale, 1..=MAX_EXPORT_SCALE)
+                                    .text("Scale")
+                                    .prefix("x"),
+                            );
+                            ui.checkbox(&mut self.save_options.embed_layers, "Embed layers in PNG")
+                                .on_hover_text("Reopening the PNG in pixeditor keeps all layers");
+                            ui.checkbox(&mut self.save_options.svg.group_layers, "Group layers in SVG");
+                            ui.separator();
+
+                            let jpeg = &mut self.save_options.jpeg;
+                            ui.label("JPEG");
+                            ui.add(egui::Slider::new(&mut jpeg.quality, 1..=100).text("Quality"));
+                            ui.horizontal(|ui| {
+                                let mut c = [jpeg.matte.r, jpeg.matte.g, jpeg.matte.b];
+                                ui.color_edit_button_rgb(&mut c);
+                                jpeg.matte = Color::new(c[0], c[1], c[2], 1.0);
+                                ui.label("Background");
+                            });
+                        });
                     });                 
+
+                    let name = if self.project_name.is_empty() { "Untitled" } else { &self.project_name };
+                    let dirty_marker = if self.is_dirty() { " *" } else { "" };
+                    ui.separator();
+                    ui.label(format!("{name}{dirty_marker}"));
                 });
 
             });
 
+            if let Some(reason) = self.close_prompt {
+                let mut choice = None;
+                egui::Window::new("Unsaved Changes")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_CENTER, (0., 0.))
+                    .show(egui_ctx, |ui| {
+                        ui.label("Save changes before closing?");
+                        ui.horizontal(|ui| {
+                            if ui.button("Save").clicked() {
+                                choice = Some(true);
+                            }
+                            if ui.button("Discard").clicked() {
+                                choice = Some(false);
+                            }
+                            if ui.button("Cancel").clicked() {
+                                self.close_prompt = None;
+                            }
+                        });
+                    });
+
+                match choice {
+                    Some(true) => {
+                        let res = self.handle_save();
+                        // Only close if the save went through (and wasn't cancelled)
+                        if res.is_ok() && !self.is_dirty() {
+                            self.close(reason);
+                        } else {
+                            self.close_prompt = None;
+                        }
+                        self.report_error(res);
+                    }
+                    Some(false) => self.close(reason),
+                    None => {}
+                }
+            }
+
+            if self.show_layer_export {
+                let mut export = false;
+                let opts = &mut self.save_options.layer_export;
+                egui::Window::new("Export Layers")
+                    .open(&mut self.show_layer_export)
+                    .collapsible(false)
+                    .resizable(false)
+                    .show(egui_ctx, |ui| {
+                        ui.label("File name pattern:");
+                        ui.text_edit_singleline(&mut opts.pattern);
+                        ui.small("{project}, {index} and {layer} are replaced. The extension picks the format.");
+                        ui.checkbox(&mut opts.skip_empty, "Skip empty layers");
+                        ui.checkbox(&mut opts.trim, "Trim to content");
+                        export = ui.button("Choose Folder and Export").clicked();
+                    });
+
+                if export {
+                    let res = self.handle_export_layers();
+                    if res.is_ok() {
+                        self.show_layer_export = false;
+                    }
+                    self.report_error(res);
+                }
+            }
+
+            if self.show_tileset_export {
+                let mut export = false;
+                let max_width = self.canvas.width() as u32;
+                let max_height = self.canvas.height() as u32;
+                let opts = &mut self.save_options.tileset;
+                egui::Window::new("Export Tileset")
+                    .open(&mut self.show_tileset_export)
+                    .collapsible(false)
+                    .resizable(false)
+                    .show(egui_ctx, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Tile size:");
+                            ui.add(egui::DragValue::new(&mut opts.tile_width).range(1..=max_width));
+                            ui.label("x");
+                            ui.add(egui::DragValue::new(&mut opts.tile_height).range(1..=max_height));
+                        });
+                        ui.checkbox(&mut opts.dedupe, "Remove duplicate tiles");
+                        ui.add_enabled(
+                            opts.dedupe,
+                            egui::Checkbox::new(&mut opts.match_transformed, "Also flipped and rotated"),
+                        );
+                        ui.checkbox(&mut opts.skip_empty, "Skip empty tiles");
+                        ui.small("A JSON index is written next to the image.");
+                        export = ui.button("Choose File and Export").clicked();
+                    });
+
+                if export {
+                    let res = self.handle_export_tileset();
+                    if res.is_ok() {
+                        self.show_tileset_export = false;
+                    }
+                    self.report_error(res);
+                }
+            }
+
+            if let Some((file_name, _)) = &self.pending_import {
+                let mut open = true;
+                let mut import = false;
+                let opts = &mut self.import_options;
+                egui::Window::new("Import as Layer")
+                    .open(&mut open)
+                    .collapsible(false)
+                    .resizable(false)
+                    .show(egui_ctx, |ui| {
+                        ui.label(file_name);
+                        ui.separator();
+
+                        ui.label("Placement");
+                        ui.radio_value(&mut opts.placement, ImportPlacement::TopLeft, "Top left");
+                        ui.radio_value(&mut opts.placement, ImportPlacement::Centered, "Centered");
+                        ui.horizontal(|ui| {
+                            ui.radio_value(&mut opts.placement, ImportPlacement::Offset, "Offset");
+                            ui.add(egui::DragValue::new(&mut opts.offset.0).prefix("x: "));
+                            ui.add(egui::DragValue::new(&mut opts.offset.1).prefix("y: "));
+                        });
+                        ui.separator();
+
+                        ui.label("If the size doesn't match the canvas");
+                        ui.radio_value(&mut opts.fit, ImportFit::Crop, "Crop");
+                        ui.radio_value(&mut opts.fit, ImportFit::Scale, "Scale to fit");
+                        ui.separator();
+
+                        import = ui.button("Import").clicked();
+                    });
+
+                if import {
+                    self.import_layer();
+                } else if !open {
+                    self.pending_import = None;
+                }
+            }
+
+            if let Some(notice) = &self.notice {
+                let mut dismissed = false;
+                egui::Window::new("Notice")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_CENTER, (0., 0.))
+                    .show(egui_ctx, |ui| {
+                        ui.label(notice);
+                        dismissed = ui.button("OK").clicked();
+                    });
+                if dismissed {
+                    self.notice = None;
+                }
+            }
+
+            if let Some(e) = &self.error {
+                let mut dismissed = false;
+                egui::Window::new("Error")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_CENTER, (0., 0.))
+                    .show(egui_ctx, |ui| {
+                        ui.label(e.to_string());
+                        dismissed = ui.button("OK").clicked();
+                    });
+                if dismissed {
+                    self.error = None;
+                }
+            }
+
             // Tools
             egui::Window::new("Tools").show(egui_ctx, |ui| {
                 use crate::icons::*;
@@ -137,47 +515,191 @@ impl Project {
                         self.canvas.set_tool(Tool::new(tk,crate::ToolInfo::default()));
                     }
                 }
+
+                if cur_tool == ToolKind::Fill {
+                    ui.separator();
+                    let mut opts = self.canvas.fill_options().clone();
+                    let mut diagonal = opts.connectivity == Connectivity::Eight;
+                    ui.checkbox(&mut diagonal, "Diagonal")
+                        .on_hover_text("Also spread to diagonal neighbours");
+                    opts.connectivity = if diagonal { Connectivity::Eight } else { Connectivity::Four };
+                    ui.label("Tolerance");
+                    ui.add(egui::Slider::new(&mut opts.tolerance, 0..=255));
+                    ui.checkbox(&mut opts.global, "Global")
+                        .on_hover_text("Fill every matching pixel, connected or not");
+                    ui.checkbox(&mut opts.sample_all_layers, "Sample all layers")
+                        .on_hover_text("Match what is seen on the canvas, not only the active layer");
+                    self.canvas.set_fill_options(opts);
+                }
             });
 
             egui::Window::new("Color").show(egui_ctx, |ui| {
                 let mut c = self.canvas.selected_color().to_vec().to_array();
                 ui.color_edit_button_rgba_unmultiplied(&mut c);
                 self.canvas.set_selected_color(Color::from_vec(c.into()));
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.label("Palette");
+                    if ui.button("Add Color").clicked() {
+                        let mut palette = self.canvas.palette().to_vec();
+                        palette.push(self.canvas.selected_color());
+                        self.set_palette(palette);
+                    }
+                    if ui.button("Load...").clicked() {
+                        let res = self.handle_load_palette();
+                        self.report_error(res);
+                    }
+                    if ui.button("Save...").clicked() {
+                        let res = self.handle_save_palette();
+                        self.report_error(res);
+                    }
+                });
+
+                let mut to_remove = None;
+                ui.horizontal_wrapped(|ui| {
+                    ui.spacing_mut().item_spacing = egui::vec2(2., 2.);
+                    for (i, color) in self.canvas.palette().iter().enumerate() {
+                        let [r, g, b, a]: [u8; 4] = (*color).into();
+                        let swatch = egui::Button::new("")
+                            .fill(egui::Color32::from_rgba_unmultiplied(r, g, b, a))
+                            .min_size(egui::vec2(16., 16.));
+                        let resp = ui.add(swatch)
+                            .on_hover_text(format!("#{r:02x}{g:02x}{b:02x}\nRight click to remove"));
+                        if resp.clicked() {
+                            c = color.to_vec().to_array();
+                        }
+                        if resp.secondary_clicked() {
+                            to_remove = Some(i);
+                        }
+                    }
+                });
+                self.canvas.set_selected_color(Color::from_vec(c.into()));
+                if let Some(i) = to_remove {
+                    let mut palette = self.canvas.palette().to_vec();
+                    palette.remove(i);
+                    self.set_palette(palette);
+                }
             });
 
 
             // Layer selection
             egui::Window::new("Layers").show(egui_ctx, |ui| {
-                if ui.button("Add new layer").clicked() {
-                    self.canvas.create_new_layer();
-                }
+                let mut command = None;
+                ui.horizontal(|ui| {
+                    if ui.button("Add new layer").clicked() {
+                        command = Some(LayerCommand::Add);
+                    }
+                    let can_flatten = self.canvas.layers().len() > 1;
+                    if ui.add_enabled(can_flatten, egui::Button::new("Flatten")).clicked() {
+                        command = Some(LayerCommand::Flatten);
+                    }
+                });
 
-                let mut layers_to_delete = Vec::<crate::canvas::LayerId>::new();
 
                 let mut selected = self.canvas.active_layer_id();
-                for (i,(id,layer)) in self.canvas.layers().iter().enumerate() {
-
-                    let layer_img = layer.to_image_unflipped();
-                    let bytes = layer_img.bytes;
-                    let img = egui::ColorImage::from_rgba_premultiplied([self.num_pixels;2],&bytes);
-                    let texture_handle = egui_ctx.load_texture(format!("layer{i}"),img,egui::TextureOptions::LINEAR);
-                    let sized_image = egui::load::SizedTexture::new(texture_handle.id(),[self.num_pixels as f32;2]);
-                    let image = egui::Image::from_texture(sized_image);
-                    
-                    ui.horizontal(|ui| {
-                        ui.add(image);
-                        ui.radio_value(&mut selected,*id,format!("Layer {i}"));
-                        if ui.button("Delete").clicked() {
-                            layers_to_delete.push(*id);
-                        }
-                    });
+
+                // Only remade when the canvas changed, and not in the middle of a stroke: big
+                // canvases are slow to upload. Layers being added or removed can't wait though.
+                let revision = self.canvas.revision();
+                let num_layers = self.canvas.layers().len();
+                let outdated = self.layer_thumbnails.as_ref().is_none_or(|(r, thumbnails)| {
+                    thumbnails.len() != num_layers
+                        || (*r != revision && !is_mouse_button_down(MouseButton::Left))
+                });
+                if outdated {
+                    let textures = self.canvas.layers().values().enumerate().map(|(i, layer)| {
+                        let layer_img = layer.to_thumbnail(THUMBNAIL_SIZE);
+                        let size = [layer_img.width as usize, layer_img.height as usize];
+                        let img = egui::ColorImage::from_rgba_unmultiplied(size, &layer_img.bytes);
+                        egui_ctx.load_texture(format!("layer{i}"), img, egui::TextureOptions::NEAREST)
+                    }).collect();
+                    self.layer_thumbnails = Some((revision, textures));
                 }
+                let thumbnails = &self.layer_thumbnails.as_ref().unwrap().1;
+
+                // Topmost layer first, like the layers are stacked on the canvas
+                for (i, texture_handle) in thumbnails.iter().enumerate().rev() {
+                    let (&id, layer) = self.canvas.layers().get_index(i).unwrap();
+                    let mut name = layer.name.clone();
+                    let (mut visible, mut locked) = (layer.visible, layer.locked);
+                    let (mut opacity, mut blend_mode) = (layer.opacity, layer.blend_mode);
 
-                for id in layers_to_delete.into_iter() {
-                    if id == self.canvas.active_layer_id() || selected == id { continue; }
-                    self.canvas.delete_layer(id);
+                    let resp = ui.vertical(|ui| {
+                        ui.horizontal(|ui| {
+                            // Dragging the preview onto another layer moves the layer there
+                            ui.dnd_drag_source(egui::Id::new(("layer drag", id)), id, |ui| {
+                                ui.add(egui::Image::from_texture(texture_handle));
+                            });
+                            ui.radio_value(&mut selected, id, "");
+                            ui.add(egui::TextEdit::singleline(&mut name).desired_width(80.));
+                            ui.checkbox(&mut visible, "Visible");
+                            ui.checkbox(&mut locked, "Lock")
+                                .on_hover_text("Locked layers can't be drawn on");
+                        });
+                        ui.horizontal(|ui| {
+                            ui.add(egui::Slider::new(&mut opacity, 0.0..=1.0).text("Opacity"));
+                            egui::ComboBox::from_id_salt(("blend mode", id))
+                                .selected_text(blend_mode.name())
+                                .show_ui(ui, |ui| {
+                                    for mode in BlendMode::ALL {
+                                        ui.selectable_value(&mut blend_mode, mode, mode.name());
+                                    }
+                                });
+                        });
+                        ui.horizontal(|ui| {
+                            if ui.add_enabled(i + 1 < num_layers, egui::Button::new("Up")).clicked() {
+                                command = Some(LayerCommand::MoveTo(id, i + 1));
+                            }
+                            if ui.add_enabled(i > 0, egui::Button::new("Down")).clicked() {
+                                command = Some(LayerCommand::MoveTo(id, i - 1));
+                            }
+                            if ui.button("Duplicate").clicked() {
+                                command = Some(LayerCommand::Duplicate(id));
+                            }
+                            let can_merge = self.canvas.can_merge_down(id);
+                            let merge = ui.add_enabled(can_merge, egui::Button::new("Merge Down"))
+                                .on_disabled_hover_text(
+                                    "Both layers have to use the Normal blend mode, and the one \
+                                     below has to be visible"
+                                );
+                            if merge.clicked() {
+                                command = Some(LayerCommand::MergeDown(id));
+                            }
+                            // The active layer can't be deleted
+                            let can_delete = id != selected;
+                            if ui.add_enabled(can_delete, egui::Button::new("Delete")).clicked() {
+                                command = Some(LayerCommand::Delete(id));
+                            }
+                        });
+                    }).response;
+                    if let Some(dragged) = resp.dnd_release_payload::<LayerId>() {
+                        command = Some(LayerCommand::MoveTo(*dragged, i));
+                    }
+                    ui.separator();
+
+                    let changed = name != layer.name
+                        || visible != layer.visible
+                        || locked != layer.locked
+                        || opacity != layer.opacity
+                        || blend_mode != layer.blend_mode;
+                    if changed {
+                        let layer = self.canvas.get_layer_mut(id).unwrap();
+                        layer.name = name;
+                        layer.visible = visible;
+                        layer.locked = locked;
+                        layer.opacity = opacity;
+                        layer.blend_mode = blend_mode;
+                        self.canvas.layers_changed();
+                        self.needs_autosave = true;
+                        self.untracked_changes = true;
+                    }
                 }
+
                 self.canvas.set_active_layer_as(selected);
+                if let Some(command) = command {
+                    self.run_layer_command(command);
+                }
             });
 
         });
@@ -189,74 +711,425 @@ impl Project {
         set_camera(&self.camera);
         self.canvas.draw(&self.camera);
         self.draw_ui();
+        self.autosave_if_needed();
     }
 
-    pub fn handle_save(&mut self) {
-       if self.save_options.name.is_none() || self.save_options.path.is_none() {
-            self.handle_save_as();
+    fn autosave_if_needed(&mut self) {
+        if !self.needs_autosave || get_time() - self.last_autosave < AUTOSAVE_INTERVAL {
             return;
+        }
+        self.last_autosave = get_time();
+
+        let res = recovery::autosave(self.session_id, &self.project_name, &self.canvas);
+        self.needs_autosave = res.is_err();
+        let already_reported = self.autosave_failed;
+        self.autosave_failed = res.is_err();
+        if !already_reported {
+            self.report_error(res.map_err(ProjectError::from));
+        }
+    }
+
+    /// Called after the project was successfully saved by the user.
+    fn on_saved(&mut self) {
+        recovery::discard(self.session_id);
+        self.needs_autosave = false;
+        self.untracked_changes = false;
+        self.action_manager.mark_saved();
+        if let Some(path) = &self.save_options.path {
+            recent_files::add(path);
+        }
+    }
+
+    /// Remembers the error (if any) so that it is shown by `draw_ui`.
+    pub fn report_error(&mut self, res: ProjectResult<()>) {
+        if let Err(e) = res {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn handle_save(&mut self) -> ProjectResult<()> {
+       if self.save_options.name.is_none() || self.save_options.path.is_none() {
+            return self.handle_save_as();
        }
-       self.save();
+       self.save()
     }
 
-    pub fn handle_save_as(&mut self) {
+    pub fn handle_save_as(&mut self) -> ProjectResult<()> {
         use native_dialog::DialogBuilder;
         use std::path::Path;
 
-        let path_res = DialogBuilder::file()
-            .set_location("~/Desktop")
-            // Currently using macroquad::texture::Image which only supports PNG
-            // TODO: Switch to use Image crate and add other extension support
-            .add_filter("PNG Image", &["png"])
+        let mut dialog = DialogBuilder::file().set_location("~/Desktop");
+        for format in ExportFormat::ALL {
+            dialog = dialog.add_filter(format.name(), format.extensions());
+        }
+        let path_res = dialog
+            .add_filter("Pixeditor Project", [PROJECT_EXTENSION])
+            .add_filter("Aseprite", ASEPRITE_EXTENSIONS)
+            .add_filter("OpenRaster", [OPENRASTER_EXTENSION])
+            .add_filter("SVG", [SVG_EXTENSION])
             .set_filename(self.save_options.name.as_ref().unwrap_or(&String::new()))
             .save_single_file()
-            .show()
-            .unwrap();
+            .show()?;
 
         // User clicked close on save window
-        if path_res.is_none() { return; }
+        let Some(path_v) = path_res else { return Ok(()) };
+
+        let mut path_string = error::path_to_string(path_v)?;
+        // Fall back to PNG if the user didn't type a known extension
+        if !is_save_path(&path_string) {
+            path_string.push('.');
+            path_string.push_str(ExportFormat::default().extensions()[0]);
+        }
+
+        self.save_to(&path_string)?;
 
-        let path_v = path_res.unwrap();
-        let path = Path::new(path_v.as_os_str());
-        let file_name = path.file_name().unwrap().to_os_string().into_string().unwrap();
-        let path_string = path.as_os_str().to_os_string().into_string().unwrap();
+        let file_name = Path::new(&path_string)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
         self.save_options.set_name(file_name);
         self.save_options.set_path(path_string);
+        self.on_saved();
+        Ok(())
+    }
+
+    /// Asks for an image to import. The import itself happens once the user has picked the import
+    /// options, see `import_layer`.
+    pub fn handle_import_layer(&mut self) -> ProjectResult<()> {
+        use native_dialog::DialogBuilder;
+
+        let path = DialogBuilder::file()
+            .set_location("~/Desktop")
+            .add_filter("Image", OPEN_IMAGE_EXTENSIONS)
+            .open_single_file()
+            .show()?;
+        let Some(path) = path else { return Ok(()) };
+
+        let img = image::open(&path)?.into_rgba8();
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        self.pending_import = Some((file_name, img));
+        Ok(())
+    }
 
-        self.save();
+    /// Adds the pending imported image as a new, active layer.
+    fn import_layer(&mut self) {
+        let Some((file_name, img)) = self.pending_import.take() else { return };
+
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        let mut layer = image_io::import_layer(&img, width, height, &self.import_options);
+        layer.name = file_stem(&file_name);
+        let layer_id = self.canvas.add_layer(layer.clone());
+        self.canvas.set_active_layer_as(layer_id);
+
+        let index = self.canvas.layers().len() - 1;
+        self.action_manager.add_action(Action::AddLayer(LayerInfo { layer_id, index, layer }));
+        self.needs_autosave = true;
     }
 
-    pub fn save(&mut self) {
-        let img = self.canvas.to_image_all();
-        // TODO: Handle these errors
-        img.export_png(self.save_options.path.as_ref().unwrap());
+    /// Replaces the project palette with one loaded from a palette file.
+    pub fn handle_load_palette(&mut self) -> ProjectResult<()> {
+        use native_dialog::DialogBuilder;
+
+        let mut dialog = DialogBuilder::file().set_location("~/Desktop");
+        for format in PaletteFormat::ALL {
+            dialog = dialog.add_filter(format.name(), [format.extension()]);
+        }
+        let Some(path) = dialog.open_single_file().show()? else { return Ok(()) };
+        let path = error::path_to_string(path)?;
+
+        let format = PaletteFormat::from_path(&path).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Unknown palette format")
+        })?;
+        let mut reader = std::io::BufReader::new(std::fs::File::open(&path)?);
+        let colors = palette::read(&mut reader, format)?;
+        self.set_palette(colors);
+        Ok(())
     }
 
+    pub fn handle_save_palette(&mut self) -> ProjectResult<()> {
+        use native_dialog::DialogBuilder;
+        use std::io::Write;
+
+        let mut dialog = DialogBuilder::file().set_location("~/Desktop");
+        for format in PaletteFormat::ALL {
+            dialog = dialog.add_filter(format.name(), [format.extension()]);
+        }
+        let Some(path) = dialog.save_single_file().show()? else { return Ok(()) };
+        let mut path = error::path_to_string(path)?;
+
+        let format = match PaletteFormat::from_path(&path) {
+            Some(f) => f,
+            None => {
+                path.push('.');
+                path.push_str(PaletteFormat::default().extension());
+                PaletteFormat::default()
+            }
+        };
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        palette::write(&mut writer, format, &self.project_name, self.canvas.palette())?;
+        Ok(writer.flush()?)
+    }
+
+    fn set_palette(&mut self, palette: Vec<Color>) {
+        self.canvas.set_palette(palette);
+        self.needs_autosave = true;
+        self.untracked_changes = true;
+    }
+
+    /// Asks for a file name and writes the tileset there, with its index next to it as JSON.
+    pub fn handle_export_tileset(&mut self) -> ProjectResult<()> {
+        use native_dialog::DialogBuilder;
+        use std::io::Write;
+
+        let path = DialogBuilder::file()
+            .set_location("~/Desktop")
+            .add_filter("PNG Image", ["png"])
+            .save_single_file()
+            .show()?;
+        let Some(mut path) = path else { return Ok(()) };
+        path.set_extension("png");
+
+        let img = image_io::rgba_image_from_flipped(&self.canvas.to_image_all());
+        let tileset = tileset::slice(&img, &self.save_options.tileset);
+        let image_path = error::path_to_string(path.clone())?;
+        let jpeg = &self.save_options.jpeg;
+        image_io::export_image(&tileset.to_image(), &image_path, ExportFormat::Png, jpeg)?;
+
+        let image_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let index_file = std::fs::File::create(path.with_extension("json"))?;
+        let mut writer = std::io::BufWriter::new(index_file);
+        tileset.write_index(&mut writer, &image_name)?;
+        writer.flush()?;
+
+        self.notice = Some(format!(
+            "Exported {} tiles for a {}x{} grid.",
+            tileset.tiles.len(),
+            tileset.grid.first().map_or(0, |r| r.len()),
+            tileset.grid.len(),
+        ));
+        Ok(())
+    }
+
+    /// Asks for a folder and writes every layer into it as a separate image.
+    pub fn handle_export_layers(&mut self) -> ProjectResult<()> {
+        use native_dialog::DialogBuilder;
+
+        let dir = DialogBuilder::file()
+            .set_location("~/Desktop")
+            .open_single_dir()
+            .show()?;
+        let Some(dir) = dir else { return Ok(()) };
+        let dir = error::path_to_string(dir)?;
+
+        layer_export::export_layers(
+            std::path::Path::new(&dir),
+            &self.project_name,
+            &self.canvas,
+            &self.save_options.layer_export,
+            self.save_options.scale,
+            &self.save_options.jpeg,
+        )?;
+        Ok(())
+    }
+
+    /// Saves to the path in `SaveOptions`, which must be set.
+    pub fn save(&mut self) -> ProjectResult<()> {
+        let path = self.save_options.path.clone().expect("save path should be set before saving");
+        self.save_to(&path)?;
+        self.on_saved();
+        Ok(())
+    }
+
+    fn save_to(&self, path: &str) -> ProjectResult<()> {
+        save_canvas(path, &self.project_name, &self.canvas, &self.save_options)
+    }
+
+    /// Applies something chosen in the "Layers" window, as an action that can be undone.
+    fn run_layer_command(&mut self, command: LayerCommand) {
+        let action = match command {
+            LayerCommand::Add => Some(Action::AddLayer(self.canvas.create_new_layer())),
+            LayerCommand::Delete(id) if id == self.canvas.active_layer_id() => None,
+            LayerCommand::Delete(id) => self.canvas.remove_layer(id).map(Action::RemoveLayer),
+            LayerCommand::MoveTo(id, to) => self.canvas.move_layer(id, to).map(Action::MoveLayer),
+            LayerCommand::Duplicate(id) => self.canvas.duplicate_layer(id).map(Action::AddLayer),
+            LayerCommand::MergeDown(id) => self.canvas.merge_down(id).map(Action::MergeLayers),
+            LayerCommand::Flatten => self.canvas.flatten().map(Action::MergeLayers),
+        };
+        if let Some(action) = action {
+            self.action_manager.add_action(action);
+            self.needs_autosave = true;
+        }
+    }
+
+    /// Undoes the last action. If it can't be undone (e.g. removing the only layer) it stays
+    /// where it is instead of moving to the redo stack.
     pub fn undo(&mut self) {
-        let act = self.action_manager.undo();
-        if act.is_none() { return; }
-        match act.unwrap() {
+        let Some(act) = self.action_manager.last_undo() else { return };
+        let applied = match act {
             Action::DrawPixels(info) => {
                 self.canvas.undo(info);
+                true
             }
+            Action::AddLayer(info) => self.canvas.remove_layer(info.layer_id).is_some(),
+            Action::RemoveLayer(info) => self.canvas.insert_layer(info),
+            Action::MoveLayer(info) => {
+                self.canvas.move_layer(info.layer_id, info.from);
+                true
+            }
+            Action::MergeLayers(info) => {
+                self.canvas.undo_merge(info);
+                true
+            }
+        };
+        if applied {
+            self.action_manager.undo();
+            self.needs_autosave = true;
         }
     }
 
     pub fn redo(&mut self) {
-        let act = self.action_manager.redo();
-        if act.is_none() { return; }
-        match act.unwrap() {
+        let Some(act) = self.action_manager.last_redo() else { return };
+        let applied = match act {
             Action::DrawPixels(info) => {
                 self.canvas.redo(info);
+                true
+            }
+            Action::AddLayer(info) => self.canvas.insert_layer(info),
+            Action::RemoveLayer(info) => self.canvas.remove_layer(info.layer_id).is_some(),
+            Action::MoveLayer(info) => {
+                self.canvas.move_layer(info.layer_id, info.to);
+                true
             }
+            Action::MergeLayers(info) => {
+                self.canvas.redo_merge(info);
+                true
+            }
+        };
+        if applied {
+            self.action_manager.redo();
+            self.needs_autosave = true;
         }
     }
 }
 
-#[derive(Default)]
+/// Returns true if `save_canvas` knows the format of `path` from its extension.
+pub fn is_save_path(path: &str) -> bool {
+    project_file::is_project_path(path)
+        || aseprite::is_aseprite_path(path)
+        || openraster::is_openraster_path(path)
+        || svg::is_svg_path(path)
+        || ExportFormat::from_path(path).is_some()
+}
+
+/// Writes `canvas` to `path`, in the format picked by its extension (PNG if it isn't a known one).
+/// Doesn't need a window, so it is shared with the command line interface.
+pub fn save_canvas(
+    path: &str,
+    project_name: &str,
+    canvas: &Canvas,
+    opts: &SaveOptions,
+) -> ProjectResult<()> {
+    use std::io::Write;
+
+    if project_file::is_project_path(path) {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        project_file::write_project(&mut writer, project_name, canvas)?;
+        return Ok(writer.flush()?);
+    }
+    if aseprite::is_aseprite_path(path) {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        aseprite::write(&mut writer, canvas)?;
+        return Ok(writer.flush()?);
+    }
+    if openraster::is_openraster_path(path) {
+        return openraster::write(std::fs::File::create(path)?, canvas);
+    }
+    if svg::is_svg_path(path) {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        svg::write(&mut writer, canvas, &opts.svg, opts.scale)?;
+        return Ok(writer.flush()?);
+    }
+
+    let format = ExportFormat::from_path(path).unwrap_or_default();
+    let img = image_io::rgba_image_from_flipped(&canvas.to_image_all());
+    let img = image_io::upscale(&img, opts.scale);
+    if format == ExportFormat::Png && opts.embed_layers {
+        return Ok(png_embed::write_png(&img, path, project_name, canvas)?);
+    }
+    Ok(image_io::export_image(&img, path, format, &opts.jpeg)?)
+}
+
+/// Loads any file the editor can open, picking the format by the extension of `path`. Returns the
+/// project name (the file name for anything but projects) and the canvas.
+///
+/// PNGs with an embedded project (see `png_embed`) are read with all of their layers. If the
+/// embedded project is broken, the flat image is read instead and the problem is a warning.
+pub fn load(path: &str) -> ProjectResult<(String, ImportedCanvas)> {
+    let imported = |canvas| ImportedCanvas { canvas, warnings: Vec::new() };
+
+    if project_file::is_project_path(path) {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        let (project_name, canvas) = project_file::read_project(&mut reader)?;
+        return Ok((project_name, imported(canvas)));
+    }
+
+    let import = if aseprite::is_aseprite_path(path) {
+        aseprite::read(&mut std::io::BufReader::new(std::fs::File::open(path)?))?
+    } else if openraster::is_openraster_path(path) {
+        openraster::read(std::io::BufReader::new(std::fs::File::open(path)?))?
+    } else {
+        let mut warnings = Vec::new();
+        if ExportFormat::from_path(path) == Some(ExportFormat::Png) {
+            match png_embed::read_embedded(path) {
+                Ok(Some((project_name, canvas))) => return Ok((project_name, imported(canvas))),
+                Ok(None) => {}
+                Err(e) => warnings.push(format!(
+                    "Could not read the layers embedded in this PNG, only the image was opened: {e}."
+                )),
+            }
+        }
+        ImportedCanvas { canvas: image_io::load_canvas(path)?, warnings }
+    };
+    Ok((file_stem(path), import))
+}
+
+/// The file name without its extension, used as the name of projects opened from other formats.
+pub fn file_stem(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
 pub struct SaveOptions {
     name:Option<String>,
-    path:Option<String>
+    path:Option<String>,
+    /// Integer upscale factor applied when exporting images. Not used for project files.
+    scale:u32,
+    /// Store the layered project inside exported PNGs. See `png_embed`.
+    embed_layers:bool,
+    jpeg:JpegOptions,
+    svg:SvgOptions,
+    layer_export:LayerExportOptions,
+    tileset:TilesetOptions,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        Self {
+            name: None,
+            path: None,
+            scale: 1,
+            embed_layers: false,
+            jpeg: JpegOptions::default(),
+            svg: SvgOptions::default(),
+            layer_export: LayerExportOptions::default(),
+            tileset: TilesetOptions::default(),
+        }
+    }
 }
 
 impl SaveOptions {
@@ -275,6 +1148,18 @@ impl SaveOptions {
         self.path = if path.is_empty() { None } else { Some(path) };
     }
 
+    pub fn set_scale(&mut self, scale:u32) {
+        self.scale = scale.clamp(1, MAX_EXPORT_SCALE);
+    }
+
+    pub fn set_embed_layers(&mut self, embed:bool) {
+        self.embed_layers = embed;
+    }
+
+    pub fn set_jpeg_quality(&mut self, quality:u8) {
+        self.jpeg.quality = quality.clamp(1, 100);
+    }
+
 }
 
 
@@ -0,0 +1,310 @@
+/// Reading and writing of the native `.pxe` project format, which (unlike exported images) keeps
+/// every layer, their order and the active layer intact.
+///
+/// Layout (all integers little-endian):
+/// - magic `PXED`, then a `u32` format version
+/// - project name (`u32` length + UTF-8 bytes)
+/// - width and height as `u32`s (before version 3 a single `u32`, as canvases were square),
+///   selected color as 4 `u8`s (RGBA)
+/// - index of the active layer and the number of layers, both `u32`
+/// - for every layer, in order: its 16 byte id, since version 4 its name (`u32` length + UTF-8
+///   bytes), flags (`u8`, 1 = visible, 2 = locked), opacity (`u8`, 0-255) and blend mode (`u8`,
+///   index into `BlendMode::ALL`), followed by `width * height` pixels, row by row, each stored as
+///   a presence byte (0 = empty) and 4 `u8`s (RGBA)
+/// - since version 2: the number of palette colors as `u32`, then the colors as 4 `u8`s each
+use std::io::{self, Read, Write};
+
+use crate::{Layer, MAX_PIXELS};
+use crate::blend::BlendMode;
+use crate::canvas::{Canvas, LayerId};
+use crate::error::invalid_data;
+use crate::palette::MAX_PALETTE_COLORS;
+
+use indexmap::IndexMap;
+use macroquad::prelude::Color;
+
+pub const PROJECT_EXTENSION: &str = "pxe";
+
+const MAGIC: &[u8; 4] = b"PXED";
+const VERSION: u32 = 4;
+
+/// Longest project or layer name we read, in bytes.
+const MAX_STRING_LEN: usize = 64 * 1024;
+
+const LAYER_FLAG_VISIBLE: u8 = 1;
+const LAYER_FLAG_LOCKED: u8 = 2;
+
+/// Returns true if `path` has the native project extension.
+pub fn is_project_path(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(PROJECT_EXTENSION))
+}
+
+pub fn write_project<W: Write>(w: &mut W, project_name: &str, canvas: &Canvas) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    write_u32(w, VERSION)?;
+    write_str(w, project_name)?;
+    write_u32(w, canvas.width() as u32)?;
+    write_u32(w, canvas.height() as u32)?;
+    write_color(w, canvas.selected_color())?;
+
+    let layers = canvas.layers();
+    let active_index = layers.get_index_of(&canvas.active_layer_id()).unwrap_or(0);
+    write_u32(w, active_index as u32)?;
+    write_u32(w, layers.len() as u32)?;
+
+    for (id, layer) in layers.iter() {
+        w.write_all(id.as_bytes())?;
+        write_str(w, &layer.name)?;
+        let mut flags = 0;
+        if layer.visible {
+            flags |= LAYER_FLAG_VISIBLE;
+        }
+        if layer.locked {
+            flags |= LAYER_FLAG_LOCKED;
+        }
+        let blend_mode = BlendMode::ALL.iter().position(|m| *m == layer.blend_mode).unwrap();
+        w.write_all(&[flags, (layer.opacity * 255.).round() as u8, blend_mode as u8])?;
+
+        for p in layer.data.iter() {
+            match p {
+                Some(c) => {
+                    w.write_all(&[1])?;
+                    write_color(w, *c)?;
+                }
+                None => w.write_all(&[0; 5])?,
+            }
+        }
+    }
+
+    write_u32(w, canvas.palette().len() as u32)?;
+    for c in canvas.palette() {
+        write_color(w, *c)?;
+    }
+    Ok(())
+}
+
+/// Returns the project name and the rebuilt canvas.
+pub fn read_project<R: Read>(r: &mut R) -> io::Result<(String, Canvas)> {
+    let (version, project_name) = read_header(r)?;
+    let width = read_u32(r)? as usize;
+    let height = if version >= 3 { read_u32(r)? as usize } else { width };
+    if !(1..=MAX_PIXELS).contains(&width) || !(1..=MAX_PIXELS).contains(&height) {
+        return Err(invalid_data(format!(
+            "project must be between 1 and {MAX_PIXELS} pixels wide and high"
+        )));
+    }
+    let selected_color = read_color(r)?;
+    let active_index = read_u32(r)? as usize;
+    let num_layers = read_u32(r)? as usize;
+    if num_layers == 0 || active_index >= num_layers {
+        return Err(invalid_data("invalid layer count"));
+    }
+
+    let mut layers = IndexMap::<LayerId, Layer>::new();
+    for i in 0..num_layers {
+        let mut id = [0u8; 16];
+        r.read_exact(&mut id)?;
+
+        // Older projects had no layer properties, the layers were named after their position
+        let mut layer = Layer::named(width, height, format!("Layer {i}"));
+        if version >= 4 {
+            layer.name = read_str(r)?;
+            let mut props = [0u8; 3];
+            r.read_exact(&mut props)?;
+            let [flags, opacity, blend_mode] = props;
+            layer.visible = flags & LAYER_FLAG_VISIBLE != 0;
+            layer.locked = flags & LAYER_FLAG_LOCKED != 0;
+            layer.opacity = opacity as f32 / 255.;
+            layer.blend_mode = *BlendMode::ALL
+                .get(blend_mode as usize)
+                .ok_or_else(|| invalid_data(format!("unknown blend mode {blend_mode}")))?;
+        }
+        for i in 0..width * height {
+            let mut present = [0u8; 1];
+            r.read_exact(&mut present)?;
+            let c = read_color(r)?;
+            if present[0] != 0 {
+                layer.set_pixel_color(i, c);
+            }
+        }
+        layers.insert(LayerId::from_bytes(id), layer);
+    }
+    if layers.len() != num_layers {
+        return Err(invalid_data("duplicate layer ids"));
+    }
+
+    let mut palette = Vec::new();
+    if version >= 2 {
+        let num_colors = read_u32(r)? as usize;
+        if num_colors > MAX_PALETTE_COLORS {
+            return Err(invalid_data("too many palette colors"));
+        }
+        for _ in 0..num_colors {
+            palette.push(read_color(r)?);
+        }
+    }
+
+    let active = *layers.get_index(active_index).unwrap().0;
+    let mut canvas = Canvas::from_layers(width, height, layers, active, selected_color);
+    canvas.set_palette(palette);
+    Ok((project_name, canvas))
+}
+
+/// Reads only as far as the project name. Used to list files without loading all of their pixels.
+pub fn read_project_name<R: Read>(r: &mut R) -> io::Result<String> {
+    Ok(read_header(r)?.1)
+}
+
+/// Returns the format version and the project name.
+fn read_header<R: Read>(r: &mut R) -> io::Result<(u32, String)> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a pixeditor project file"));
+    }
+
+    let version = read_u32(r)?;
+    if version > VERSION {
+        return Err(invalid_data(format!("unsupported project version {version}")));
+    }
+
+    Ok((version, read_str(r)?))
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_u32(w, s.len() as u32)?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_color<W: Write>(w: &mut W, c: Color) -> io::Result<()> {
+    let rgba: [u8; 4] = c.into();
+    w.write_all(&rgba)
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+/// Reads through `take` so that a broken length can't make us allocate gigabytes up front.
+fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_u32(r)? as usize;
+    if len > MAX_STRING_LEN {
+        return Err(invalid_data("name too long"));
+    }
+    let mut b = Vec::new();
+    r.take(len as u64).read_to_end(&mut b)?;
+    if b.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(b).map_err(invalid_data)
+}
+
+fn read_color<R: Read>(r: &mut R) -> io::Result<Color> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(Color::from_rgba(b[0], b[1], b[2], b[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(c: Option<Color>) -> Option<[u8; 4]> {
+        c.map(|c| c.into())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut canvas = Canvas::new(3, 2);
+        let bottom = canvas.active_layer_id();
+        canvas.set_pixel_at(0, Some(Color::from_rgba(10, 20, 30, 255)));
+        canvas.set_pixel_at(5, Some(Color::from_rgba(200, 100, 0, 128)));
+        let top = canvas.create_new_layer().layer_id;
+        let layer = canvas.get_layer_mut(top).unwrap();
+        layer.name = "Shading".to_string();
+        layer.visible = false;
+        layer.locked = true;
+        layer.opacity = 51. / 255.;
+        layer.blend_mode = BlendMode::Multiply;
+        layer.set_pixel_color(3, Color::from_rgba(1, 2, 3, 4));
+        canvas.set_active_layer_as(top);
+        canvas.set_palette(vec![Color::from_rgba(255, 0, 0, 255), Color::from_rgba(0, 0, 9, 255)]);
+
+        let mut bytes = Vec::new();
+        write_project(&mut bytes, "My sprite", &canvas).unwrap();
+        let (name, read) = read_project(&mut &bytes[..]).unwrap();
+
+        assert_eq!(name, "My sprite");
+        assert_eq!((read.width(), read.height()), (3, 2));
+        assert_eq!(read.active_layer_id(), top);
+        assert_eq!(read.layers().keys().copied().collect::<Vec<_>>(), vec![bottom, top]);
+        for (a, b) in canvas.layers().values().zip(read.layers().values()) {
+            assert_eq!(a.name, b.name);
+            assert_eq!((a.visible, a.locked, a.blend_mode), (b.visible, b.locked, b.blend_mode));
+            assert_eq!(a.opacity, b.opacity);
+            let pixels = |l: &Layer| l.data.iter().map(|c| rgba(*c)).collect::<Vec<_>>();
+            assert_eq!(pixels(a), pixels(b));
+        }
+        let palette = |c: &Canvas| c.palette().iter().map(|c| rgba(Some(*c))).collect::<Vec<_>>();
+        assert_eq!(palette(&read), palette(&canvas));
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(b"old");
+        // A single size, canvases were square
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 255]);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&[7; 16]);
+        for i in 0..4 {
+            if i == 1 {
+                bytes.extend_from_slice(&[1, 255, 0, 0, 255]);
+            } else {
+                bytes.extend_from_slice(&[0; 5]);
+            }
+        }
+
+        let (name, canvas) = read_project(&mut &bytes[..]).unwrap();
+        assert_eq!(name, "old");
+        assert_eq!((canvas.width(), canvas.height()), (2, 2));
+        assert!(canvas.palette().is_empty());
+        let layer = canvas.active_layer().unwrap();
+        assert_eq!(canvas.active_layer_id(), LayerId::from_bytes([7; 16]));
+        assert_eq!(layer.name, "Layer 0");
+        assert!(layer.visible && !layer.locked);
+        assert_eq!(layer.blend_mode, BlendMode::Normal);
+        let pixels = layer.data.iter().map(|c| rgba(*c)).collect::<Vec<_>>();
+        assert_eq!(pixels, vec![None, Some([255, 0, 0, 255]), None, None]);
+    }
+
+    #[test]
+    fn rejects_oversized_projects() {
+        let canvas = Canvas::new(1, 1);
+        let mut bytes = Vec::new();
+        write_project(&mut bytes, "", &canvas).unwrap();
+        // The width comes right after the magic, version and empty name
+        bytes[12..16].copy_from_slice(&(MAX_PIXELS as u32 + 1).to_le_bytes());
+        let Err(err) = read_project(&mut &bytes[..]) else { panic!("read a too wide project") };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_project(&mut &bytes[..]).is_err());
+    }
+}
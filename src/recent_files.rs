@@ -0,0 +1,55 @@
+/// The most-recently-used list of opened/saved files shown on the main menu, stored one path per
+/// line in `<config dir>/pixeditor/recent_files.txt`.
+///
+/// Failing to read or write the list is not worth bothering the user about, so errors are
+/// ignored.
+use std::path::{Path, PathBuf};
+
+pub const MAX_RECENT_FILES: usize = 10;
+
+fn config_path() -> Option<PathBuf> {
+    Some(dirs::config_dir()?.join("pixeditor").join("recent_files.txt"))
+}
+
+/// Returns the recent files, most recent first. Files that no longer exist are left out (and
+/// removed from the stored list).
+pub fn load() -> Vec<String> {
+    let Some(contents) = config_path().and_then(|p| std::fs::read_to_string(p).ok()) else {
+        return Vec::new();
+    };
+
+    let all: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+    let existing: Vec<String> = all
+        .iter()
+        .filter(|p| Path::new(p).exists())
+        .map(|p| p.to_string())
+        .collect();
+
+    if existing.len() != all.len() {
+        store(&existing);
+    }
+    existing
+}
+
+/// Moves (or adds) `path` to the top of the list.
+pub fn add(path: &str) {
+    let mut files = load();
+    files.retain(|p| p != path);
+    files.insert(0, path.to_string());
+    files.truncate(MAX_RECENT_FILES);
+    store(&files);
+}
+
+pub fn remove(path: &str) {
+    let mut files = load();
+    files.retain(|p| p != path);
+    store(&files);
+}
+
+fn store(files: &[String]) {
+    let Some(path) = config_path() else { return };
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(path, files.join("\n"));
+}
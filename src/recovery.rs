@@ -0,0 +1,91 @@
+/// Autosaving of open projects and restoring them after a crash.
+///
+/// Every open project gets a session id and is periodically written (in the native project
+/// format) to `<data dir>/pixeditor/recovery/<session id>.pxe`. The file is removed once the
+/// project is explicitly saved, so any file still there on startup belongs to a session that
+/// didn't end cleanly.
+use std::path::{Path, PathBuf};
+
+use crate::project_file::{self, PROJECT_EXTENSION};
+use crate::canvas::Canvas;
+
+use uuid::Uuid;
+
+/// Seconds between autosaves of a project with unsaved changes.
+pub const AUTOSAVE_INTERVAL: f64 = 30.0;
+
+pub type SessionId = Uuid;
+
+#[derive(Debug, Clone)]
+pub struct RecoveredSession {
+    pub id: SessionId,
+    pub path: PathBuf,
+    pub project_name: String,
+    pub modified: Option<std::time::SystemTime>,
+}
+
+pub fn recovery_dir() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("pixeditor").join("recovery"))
+}
+
+pub fn session_path(id: SessionId) -> Option<PathBuf> {
+    Some(recovery_dir()?.join(format!("{id}.{PROJECT_EXTENSION}")))
+}
+
+/// Writes to a temporary file first, so that a crash while autosaving can't destroy the previous
+/// autosave.
+pub fn autosave(id: SessionId, project_name: &str, canvas: &Canvas) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let Some(path) = session_path(id) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no data directory to store recovery files in",
+        ));
+    };
+    std::fs::create_dir_all(path.parent().unwrap())?;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+    project_file::write_project(&mut writer, project_name, canvas)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(tmp_path, path)
+}
+
+/// Removes the recovery file of a session, if there is one.
+pub fn discard(id: SessionId) {
+    if let Some(path) = session_path(id) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Lists the sessions that can be restored, newest first. Unreadable files are skipped.
+pub fn list_sessions() -> Vec<RecoveredSession> {
+    let Some(dir) = recovery_dir() else { return Vec::new() };
+    let Ok(entries) = std::fs::read_dir(dir) else { return Vec::new() };
+
+    let mut sessions: Vec<RecoveredSession> = entries
+        .filter_map(|e| read_session(&e.ok()?.path()))
+        .collect();
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.modified));
+    sessions
+}
+
+fn read_session(path: &Path) -> Option<RecoveredSession> {
+    if !project_file::is_project_path(path.to_str()?) {
+        return None;
+    }
+    let id = SessionId::parse_str(path.file_stem()?.to_str()?).ok()?;
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path).ok()?);
+    let project_name = project_file::read_project_name(&mut reader).ok()?;
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    Some(RecoveredSession {
+        id,
+        path: path.to_path_buf(),
+        project_name,
+        modified,
+    })
+}
@@ -0,0 +1,139 @@
+/// Exporting the canvas as an SVG made of `<rect>` elements, for when sprites need to be scaled
+/// freely, e.g. on the web or in print.
+///
+/// Pixels of the same color are merged into as few rectangles as possible to keep the files
+/// small.
+use std::io::{self, Write};
+
+use crate::blend::BlendMode;
+use crate::canvas::Canvas;
+use crate::image_io;
+
+use image::RgbaImage;
+
+pub const SVG_EXTENSION: &str = "svg";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SvgOptions {
+    /// Put the rectangles of every layer in their own `<g>`, instead of drawing the flattened
+    /// canvas.
+    pub group_layers: bool,
+}
+
+pub fn is_svg_path(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case(SVG_EXTENSION))
+}
+
+/// Writes the canvas as an SVG. `scale` only sets the default display size, the drawing itself
+/// uses one unit per pixel.
+pub fn write<W: Write>(w: &mut W, canvas: &Canvas, opts: &SvgOptions, scale: u32) -> io::Result<()> {
+    let (width, height) = (canvas.width(), canvas.height());
+    let scale = scale.max(1) as usize;
+    writeln!(
+        w,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {width} {height}\" shape-rendering=\"crispEdges\">",
+        width * scale,
+        height * scale,
+    )?;
+
+    if opts.group_layers {
+        // Later elements are drawn on top, same as our layer order
+        for (i, (_id, layer)) in canvas.layers().iter().enumerate() {
+            if layer.is_empty() {
+                continue;
+            }
+            write!(w, "  <g id=\"layer{i}\"")?;
+            if !layer.visible {
+                write!(w, " display=\"none\"")?;
+            }
+            if layer.opacity < 1.0 {
+                write!(w, " opacity=\"{:.3}\"", layer.opacity)?;
+            }
+            if let Some(mode) = mix_blend_mode(layer.blend_mode) {
+                write!(w, " style=\"mix-blend-mode:{mode}\"")?;
+            }
+            writeln!(w, ">")?;
+            write_rects(w, &image_io::rgba_image(&layer.to_image_unflipped()), "    ")?;
+            writeln!(w, "  </g>")?;
+        }
+    } else {
+        let img = image_io::rgba_image_from_flipped(&canvas.to_image_all());
+        write_rects(w, &img, "  ")?;
+    }
+
+    writeln!(w, "</svg>")
+}
+
+/// The CSS name of a blend mode, None for normal blending.
+fn mix_blend_mode(mode: BlendMode) -> Option<&'static str> {
+    match mode {
+        BlendMode::Normal => None,
+        BlendMode::Multiply => Some("multiply"),
+        BlendMode::Screen => Some("screen"),
+        BlendMode::Overlay => Some("overlay"),
+        BlendMode::Add => Some("plus-lighter"),
+    }
+}
+
+fn write_rects<W: Write>(w: &mut W, img: &RgbaImage, indent: &str) -> io::Result<()> {
+    for rect in merge_rects(img) {
+        let [r, g, b, a] = rect.color;
+        write!(
+            w,
+            "{indent}<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#{r:02x}{g:02x}{b:02x}\"",
+            rect.x, rect.y, rect.width, rect.height
+        )?;
+        if a < u8::MAX {
+            write!(w, " fill-opacity=\"{:.3}\"", a as f32 / 255.)?;
+        }
+        writeln!(w, "/>")?;
+    }
+    Ok(())
+}
+
+struct ColorRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    color: [u8; 4],
+}
+
+/// Greedily covers the non-transparent pixels of `img` with rectangles of a single color: each
+/// rectangle is grown right as far as the color continues, then down for as long as the whole
+/// row below matches.
+fn merge_rects(img: &RgbaImage) -> Vec<ColorRect> {
+    let (width, height) = img.dimensions();
+    let mut covered = vec![false; (width * height) as usize];
+    let is_free = |covered: &[bool], x: u32, y: u32, color: [u8; 4]| {
+        !covered[(x + y * width) as usize] && img.get_pixel(x, y).0 == color
+    };
+
+    let mut rects = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let color = img.get_pixel(x, y).0;
+            if color[3] == 0 || covered[(x + y * width) as usize] {
+                continue;
+            }
+
+            let mut w = 1;
+            while x + w < width && is_free(&covered, x + w, y, color) {
+                w += 1;
+            }
+            let mut h = 1;
+            while y + h < height && (x..x + w).all(|cx| is_free(&covered, cx, y + h, color)) {
+                h += 1;
+            }
+
+            for cy in y..y + h {
+                let start = (x + cy * width) as usize;
+                covered[start..start + w as usize].fill(true);
+            }
+            rects.push(ColorRect { x, y, width: w, height: h, color });
+        }
+    }
+    rects
+}
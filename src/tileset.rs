@@ -0,0 +1,270 @@
+/// Cutting the flattened canvas into tiles and packing them into a tileset image, with a JSON
+/// index that tells where every tile of the canvas went.
+///
+/// The index looks like this, with one entry per grid cell, row by row. Empty cells are `null`,
+/// and tiles that were deduplicated against a flipped or rotated tile say how to get back the
+/// original: flip horizontally first (if `flip_x`), then rotate clockwise by `rotation` degrees.
+///
+/// ```json
+/// {
+///   "image": "tiles.png",
+///   "tile_width": 8, "tile_height": 8, "tile_count": 2, "tileset_columns": 2,
+///   "columns": 2, "rows": 1,
+///   "grid": [
+///     [{"tile": 0, "flip_x": false, "rotation": 0}, {"tile": 1, "flip_x": true, "rotation": 90}]
+///   ]
+/// }
+/// ```
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use image::RgbaImage;
+use image::imageops;
+
+#[derive(Debug, Clone)]
+pub struct TilesetOptions {
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// Store identical tiles only once.
+    pub dedupe: bool,
+    /// Also count tiles as duplicates if they match after flipping or rotating. Rotations are only
+    /// tried for square tiles.
+    pub match_transformed: bool,
+    /// Leave fully transparent tiles out of the tileset, they are `null` in the index.
+    pub skip_empty: bool,
+}
+
+impl Default for TilesetOptions {
+    fn default() -> Self {
+        Self {
+            tile_width: 8,
+            tile_height: 8,
+            dedupe: true,
+            match_transformed: false,
+            skip_empty: true,
+        }
+    }
+}
+
+/// How a stored tile has to be transformed to look like the grid cell.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TileTransform {
+    pub flip_x: bool,
+    /// Clockwise, in degrees. Always 0, 90, 180 or 270.
+    pub rotation: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileRef {
+    pub tile: usize,
+    pub transform: TileTransform,
+}
+
+pub struct Tileset {
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tiles: Vec<RgbaImage>,
+    /// Rows of grid cells, top to bottom.
+    pub grid: Vec<Vec<Option<TileRef>>>,
+}
+
+/// Slices `img` into tiles. Tiles at the right and bottom edges that don't fit completely are
+/// padded with transparency.
+pub fn slice(img: &RgbaImage, opts: &TilesetOptions) -> Tileset {
+    let (tw, th) = (opts.tile_width.max(1), opts.tile_height.max(1));
+    let columns = img.width().div_ceil(tw);
+    let rows = img.height().div_ceil(th);
+
+    let mut tiles = Vec::new();
+    // Pixels of every known tile (and its transformed versions) to where it is stored
+    let mut known = HashMap::<Vec<u8>, TileRef>::new();
+    let mut grid = Vec::with_capacity(rows as usize);
+
+    for row in 0..rows {
+        let mut cells = Vec::with_capacity(columns as usize);
+        for column in 0..columns {
+            let mut tile = RgbaImage::new(tw, th);
+            let view = imageops::crop_imm(img, column * tw, row * th, tw, th);
+            imageops::replace(&mut tile, &*view, 0, 0);
+
+            if opts.skip_empty && tile.pixels().all(|p| p[3] == 0) {
+                cells.push(None);
+                continue;
+            }
+            if opts.dedupe
+                && let Some(found) = known.get(tile.as_raw())
+            {
+                cells.push(Some(*found));
+                continue;
+            }
+
+            let id = tiles.len();
+            if opts.dedupe {
+                for transform in transforms(tw == th, opts.match_transformed) {
+                    let tile_ref = TileRef { tile: id, transform };
+                    known
+                        .entry(apply(&tile, transform).into_raw())
+                        .or_insert(tile_ref);
+                }
+            }
+            tiles.push(tile);
+            cells.push(Some(TileRef { tile: id, transform: TileTransform::default() }));
+        }
+        grid.push(cells);
+    }
+
+    Tileset { tile_width: tw, tile_height: th, tiles, grid }
+}
+
+/// The transforms to try when looking for duplicates, starting with doing nothing so that exact
+/// duplicates are preferred.
+fn transforms(square: bool, all: bool) -> Vec<TileTransform> {
+    if !all {
+        return vec![TileTransform::default()];
+    }
+    let rotations: &[u16] = if square { &[0, 90, 180, 270] } else { &[0, 180] };
+    [false, true]
+        .into_iter()
+        .flat_map(|flip_x| rotations.iter().map(move |&rotation| TileTransform { flip_x, rotation }))
+        .collect()
+}
+
+fn apply(tile: &RgbaImage, transform: TileTransform) -> RgbaImage {
+    let tile = if transform.flip_x { imageops::flip_horizontal(tile) } else { tile.clone() };
+    match transform.rotation {
+        90 => imageops::rotate90(&tile),
+        180 => imageops::rotate180(&tile),
+        270 => imageops::rotate270(&tile),
+        _ => tile,
+    }
+}
+
+impl Tileset {
+    /// Tiles per row of the packed image, chosen to make it roughly square.
+    pub fn image_columns(&self) -> u32 {
+        (self.tiles.len() as f64).sqrt().ceil().max(1.) as u32
+    }
+
+    /// All tiles packed into one image, left to right and top to bottom in id order.
+    pub fn to_image(&self) -> RgbaImage {
+        let columns = self.image_columns();
+        let rows = (self.tiles.len() as u32).div_ceil(columns).max(1);
+        let mut img = RgbaImage::new(columns * self.tile_width, rows * self.tile_height);
+        for (i, tile) in self.tiles.iter().enumerate() {
+            let (x, y) = (i as u32 % columns, i as u32 / columns);
+            imageops::replace(
+                &mut img,
+                tile,
+                (x * self.tile_width) as i64,
+                (y * self.tile_height) as i64,
+            );
+        }
+        img
+    }
+
+    /// Writes the JSON index. `image_name` is the file name of the packed image.
+    pub fn write_index<W: Write>(&self, w: &mut W, image_name: &str) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"image\": \"{}\",", escape_json(image_name))?;
+        writeln!(
+            w,
+            "  \"tile_width\": {}, \"tile_height\": {}, \"tile_count\": {}, \"tileset_columns\": {},",
+            self.tile_width,
+            self.tile_height,
+            self.tiles.len(),
+            self.image_columns()
+        )?;
+        let columns = self.grid.first().map_or(0, |r| r.len());
+        writeln!(w, "  \"columns\": {columns}, \"rows\": {},", self.grid.len())?;
+        writeln!(w, "  \"grid\": [")?;
+        for (i, row) in self.grid.iter().enumerate() {
+            let cells = row
+                .iter()
+                .map(|cell| match cell {
+                    Some(r) => format!(
+                        "{{\"tile\": {}, \"flip_x\": {}, \"rotation\": {}}}",
+                        r.tile, r.transform.flip_x, r.transform.rotation
+                    ),
+                    None => "null".to_string(),
+                })
+                .collect::<Vec<_>>();
+            let comma = if i + 1 < self.grid.len() { "," } else { "" };
+            writeln!(w, "    [{}]{comma}", cells.join(", "))?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /// A 2x2 tile with one opaque pixel in the corner given by `x` and `y`.
+    fn corner(x: u32, y: u32) -> RgbaImage {
+        let mut tile = RgbaImage::new(2, 2);
+        tile.put_pixel(x, y, Rgba([255, 0, 0, 255]));
+        tile
+    }
+
+    /// Puts `tiles` next to each other.
+    fn row(tiles: &[RgbaImage]) -> RgbaImage {
+        let mut img = RgbaImage::new(2 * tiles.len() as u32, 2);
+        for (i, tile) in tiles.iter().enumerate() {
+            imageops::replace(&mut img, tile, 2 * i as i64, 0);
+        }
+        img
+    }
+
+    fn opts(match_transformed: bool) -> TilesetOptions {
+        TilesetOptions { tile_width: 2, tile_height: 2, match_transformed, ..Default::default() }
+    }
+
+    #[test]
+    fn dedupes_and_skips_empty_tiles() {
+        let img = row(&[corner(0, 0), RgbaImage::new(2, 2), corner(0, 0), corner(1, 0)]);
+        let tileset = slice(&img, &opts(false));
+        assert_eq!(tileset.tiles.len(), 2);
+
+        let tiles = tileset.grid[0].iter().map(|c| c.map(|c| c.tile)).collect::<Vec<_>>();
+        assert_eq!(tiles, vec![Some(0), None, Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn matches_transformed_tiles() {
+        let img = row(&[corner(0, 0), corner(1, 0), corner(1, 1)]);
+        let tileset = slice(&img, &opts(true));
+        assert_eq!(tileset.tiles.len(), 1);
+
+        // Every cell has to come back out of the stored tile with its transform
+        for (i, cell) in tileset.grid[0].iter().enumerate() {
+            let cell = cell.unwrap();
+            let restored = apply(&tileset.tiles[cell.tile], cell.transform);
+            let original = imageops::crop_imm(&img, 2 * i as u32, 0, 2, 2).to_image();
+            assert_eq!(restored, original);
+        }
+    }
+
+    #[test]
+    fn pads_partial_tiles() {
+        let img = RgbaImage::from_pixel(3, 1, Rgba([1, 2, 3, 255]));
+        let tileset = slice(&img, &opts(false));
+        assert_eq!(tileset.grid.len(), 1);
+        assert_eq!(tileset.grid[0].len(), 2);
+        assert_eq!(*tileset.tiles[1].get_pixel(1, 0), Rgba([0, 0, 0, 0]));
+    }
+}
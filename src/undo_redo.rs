@@ -1,8 +1,38 @@
-use crate::{ Color, canvas::LayerId };
+use crate::{ Color, Layer, canvas::LayerId };
 
 #[derive(Debug,Clone,PartialEq)]
 pub enum Action {
     DrawPixels(DrawPixelsInfo),
+    AddLayer(LayerInfo),
+    RemoveLayer(LayerInfo),
+    MoveLayer(MoveLayerInfo),
+    MergeLayers(MergeLayersInfo),
+}
+
+/// A whole layer, along with where it is in the layer order. Needed to bring back removed layers.
+#[derive(Debug,Clone,PartialEq)]
+pub struct LayerInfo {
+    pub layer_id:LayerId,
+    pub index:usize,
+    pub layer:Layer,
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct MoveLayerInfo {
+    pub layer_id:LayerId,
+    pub from:usize,
+    pub to:usize,
+}
+
+/// Layers that were merged into `target_id` (merge down or flatten). Keeps both versions of the
+/// target layer so that undo and redo don't have to blend again.
+#[derive(Debug,Clone,PartialEq)]
+pub struct MergeLayersInfo {
+    pub target_id:LayerId,
+    pub before:Layer,
+    pub after:Layer,
+    /// The layers that went into the target, in ascending index order.
+    pub removed:Vec<LayerInfo>,
 }
 
 #[derive(Debug,Clone,PartialEq)]
@@ -25,6 +55,11 @@ impl DrawPixelsInfo {
             layer_id,
         }
     }
+
+    /// True if no pixel actually changed color, e.g. when drawing over a pixel again.
+    pub fn is_noop(&self) -> bool {
+        self.pixels.iter().all(|(_,info)| info.from == info.to)
+    }
 }
 
 impl DrawInfo {
@@ -44,14 +79,27 @@ impl Action {
     pub fn get_layer_id(&self) -> Option<LayerId> {
         match self {
             Action::DrawPixels(info) => Some(info.layer_id),
+            Action::AddLayer(info) => Some(info.layer_id),
+            Action::RemoveLayer(info) => Some(info.layer_id),
+            Action::MoveLayer(info) => Some(info.layer_id),
+            Action::MergeLayers(info) => Some(info.target_id),
         }
     }
 }
 
-#[derive(Debug,Default,Clone)]
+#[derive(Debug,Clone)]
 pub struct ActionsManager {
     undo_buf:Vec<Action>,
     redo_buf:Vec<Action>,
+    /// Length of `undo_buf` when the project was last saved. None if the saved state can no
+    /// longer be reached by undoing or redoing.
+    saved_len:Option<usize>,
+}
+
+impl Default for ActionsManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ActionsManager {
@@ -59,9 +107,20 @@ impl ActionsManager {
         Self {
             undo_buf: Vec::new(),
             redo_buf: Vec::new(),
+            saved_len: Some(0),
         }
     }
 
+    /// The action `undo` would undo next.
+    pub fn last_undo(&self) -> Option<&Action> {
+        self.undo_buf.last()
+    }
+
+    /// The action `redo` would redo next.
+    pub fn last_redo(&self) -> Option<&Action> {
+        self.redo_buf.last()
+    }
+
     pub fn undo(&mut self) -> Option<&Action> {
         if let Some(act) = self.undo_buf.pop() {
             self.redo_buf.push(act);
@@ -79,9 +138,23 @@ impl ActionsManager {
     }
 
     pub fn add_action(&mut self, action:Action) {
+        // The saved state is being thrown away along with the redo buffer
+        if self.saved_len.is_some_and(|l| l > self.undo_buf.len()) {
+            self.saved_len = None;
+        }
         self.undo_buf.push(action);
         self.redo_buf.clear();
     }
 
+    /// Marks the current state as the saved one.
+    pub fn mark_saved(&mut self) {
+        self.saved_len = Some(self.undo_buf.len());
+    }
+
+    /// If undoing/redoing has moved away from the last saved state.
+    pub fn is_dirty(&self) -> bool {
+        self.saved_len != Some(self.undo_buf.len())
+    }
+
 }
 